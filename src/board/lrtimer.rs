@@ -37,6 +37,15 @@ pub const COUNTS_PER_MILLISECOND: u16 = 2;
 /// Timer instant type.
 pub type Instant = embedded_time::Instant<LrTimer>;
 
+/// Error returned when the timer clock frequency is not as expected.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClockError {
+    /// Expected `TIM2CLK` frequency, in Hertz.
+    pub expected_hz: u32,
+    /// Actual `TIM2CLK` frequency, in Hertz.
+    pub actual_hz: u32,
+}
+
 pub struct LrTimer {
     /// Hardware timer associated with this software timer.
     tim: timer::CountDownTimer<pac::TIM2>,
@@ -59,16 +68,36 @@ impl LrTimer {
     ///
     /// The timer is running after this function returns.
     pub fn new(tim: pac::TIM2, clocks: &Clocks) -> Self {
-        assert!(clocks.pclk1_tim().0 == TIM2CLK_EXPECTED_HZ);
+        Self::try_new(tim, clocks).unwrap()
+    }
+
+    /// Creates a new LrTimer, returning an error if the timer frequency
+    /// mismatches.
+    ///
+    /// The timer is running after this function returns `Ok()`.
+    pub fn try_new(tim: pac::TIM2, clocks: &Clocks) -> Result<Self, ClockError> {
+        Self::check_frequency(clocks.pclk1_tim().0)?;
 
         let mut timer = timer::Timer::tim2(tim, clocks).start_raw(PRESCALER_VALUE, RELOAD_VALUE);
         // Not sound: this is a bit of a race - but it works if we use it in
         // RTIC.
         timer.listen(timer::Event::Update);
 
-        Self {
+        Ok(Self {
             tim: timer,
             updates: 0,
+        })
+    }
+
+    /// Checks that the `TIM2CLK` frequency is the one the timer assumes.
+    fn check_frequency(actual_hz: u32) -> Result<(), ClockError> {
+        if actual_hz == TIM2CLK_EXPECTED_HZ {
+            Ok(())
+        } else {
+            Err(ClockError {
+                expected_hz: TIM2CLK_EXPECTED_HZ,
+                actual_hz,
+            })
         }
     }
 
@@ -119,6 +148,36 @@ impl LrTimer {
         Self::calculate_ms(updates, cnt)
     }
 
+    /// Checks that the timer is running and its update handling works.
+    ///
+    /// Reads `ms()` up to `spins` times, verifying that the value never goes
+    /// backwards and that it advances at least once. Returns `false` if
+    /// either condition is violated, which indicates a dead or misconfigured
+    /// timer.
+    ///
+    /// Takes up to a few milliseconds to complete, depending on `spins`.
+    /// Meant to be called once during initialization.
+    pub fn self_check(&mut self, spins: u32) -> bool {
+        let start = self.ms();
+        let mut last = start;
+
+        for _ in 0..spins {
+            let now = self.ms();
+            // Treat anything more than half the range as having gone
+            // backwards.
+            if now.wrapping_sub(last) > u32::MAX / 2 {
+                return false;
+            }
+            last = now;
+
+            if last != start {
+                return true;
+            }
+        }
+
+        false
+    }
+
     /// Equivalent to `ms()`, with the added exception that an error can be
     /// returned if the timer overflows.
     ///
@@ -202,3 +261,24 @@ where
         while self.ms().wrapping_sub(start) < ms {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_expected_frequency() {
+        assert_eq!(LrTimer::check_frequency(TIM2CLK_EXPECTED_HZ), Ok(()));
+    }
+
+    #[test]
+    fn rejects_mismatched_frequency() {
+        assert_eq!(
+            LrTimer::check_frequency(36_000_000),
+            Err(ClockError {
+                expected_hz: TIM2CLK_EXPECTED_HZ,
+                actual_hz: 36_000_000,
+            })
+        );
+    }
+}
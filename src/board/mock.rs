@@ -0,0 +1,171 @@
+/// Mock peripherals for host-side tests.
+///
+/// Every mock shares its state between clones, so that a test can keep a
+/// handle on a peripheral after moving it into a driver.
+use core::{
+    cell::{Cell, RefCell},
+    convert::Infallible,
+};
+use embedded_hal::{
+    digital::v2::{OutputPin, StatefulOutputPin},
+    Direction, Pwm, Qei,
+};
+use embedded_time::{clock, fraction::Fraction, Clock, Instant};
+use std::rc::Rc;
+use stm32f1xx_hal::{pwm::Channel, time::Hertz};
+
+/// Microsecond clock, standing in for a high-resolution timer.
+///
+/// Instants are created directly through `Instant::new()`.
+#[derive(Debug)]
+pub struct UsClock;
+
+impl Clock for UsClock {
+    type T = u32;
+
+    const SCALING_FACTOR: Fraction = Fraction::new(1, 1_000_000);
+
+    fn try_now(&self) -> Result<Instant<Self>, clock::Error> {
+        Ok(Instant::new(0))
+    }
+}
+
+/// Output pin.
+#[derive(Clone, Default)]
+pub struct Pin(Rc<Cell<bool>>);
+
+impl Pin {
+    /// Determines if the pin is currently driven high.
+    pub fn is_high(&self) -> bool {
+        self.0.get()
+    }
+}
+
+impl OutputPin for Pin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set(true);
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for Pin {
+    fn is_set_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.0.get())
+    }
+
+    fn is_set_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.0.get())
+    }
+}
+
+/// State of a `MockPwm`, indexed by channel.
+pub struct PwmState {
+    pub max_duty: u16,
+    pub period: Hertz,
+    pub duties: [u16; 4],
+    pub enabled: [bool; 4],
+}
+
+/// Four-channel PWM generator.
+#[derive(Clone)]
+pub struct MockPwm(Rc<RefCell<PwmState>>);
+
+impl MockPwm {
+    /// Creates a new PWM generator with all channels disabled.
+    pub fn new(max_duty: u16) -> Self {
+        Self(Rc::new(RefCell::new(PwmState {
+            max_duty,
+            period: Hertz(0),
+            duties: [0; 4],
+            enabled: [false; 4],
+        })))
+    }
+
+    /// Obtain the duty cycle last set on a channel.
+    pub fn duty(&self, channel: Channel) -> u16 {
+        self.0.borrow().duties[index(channel)]
+    }
+
+    /// Determines if a channel is enabled.
+    pub fn is_enabled(&self, channel: Channel) -> bool {
+        self.0.borrow().enabled[index(channel)]
+    }
+}
+
+/// Obtain the index of a PWM channel.
+fn index(channel: Channel) -> usize {
+    match channel {
+        Channel::C1 => 0,
+        Channel::C2 => 1,
+        Channel::C3 => 2,
+        Channel::C4 => 3,
+    }
+}
+
+impl Pwm for MockPwm {
+    type Channel = Channel;
+    type Time = Hertz;
+    type Duty = u16;
+
+    fn disable(&mut self, channel: Channel) {
+        self.0.borrow_mut().enabled[index(channel)] = false;
+    }
+
+    fn enable(&mut self, channel: Channel) {
+        self.0.borrow_mut().enabled[index(channel)] = true;
+    }
+
+    fn get_period(&self) -> Hertz {
+        self.0.borrow().period
+    }
+
+    fn get_duty(&self, channel: Channel) -> u16 {
+        self.duty(channel)
+    }
+
+    fn get_max_duty(&self) -> u16 {
+        self.0.borrow().max_duty
+    }
+
+    fn set_duty(&mut self, channel: Channel, duty: u16) {
+        self.0.borrow_mut().duties[index(channel)] = duty;
+    }
+
+    fn set_period<P: Into<Hertz>>(&mut self, period: P) {
+        self.0.borrow_mut().period = period.into();
+    }
+}
+
+/// Quadrature encoder with a 16 bit counter.
+#[derive(Clone, Default)]
+pub struct MockQei(Rc<Cell<u16>>);
+
+impl MockQei {
+    /// Moves the counter by `delta` counts, wrapping around like the hardware
+    /// counter.
+    ///
+    /// Moves of more than a quarter of the counter range between samples may
+    /// be rejected by `QeiManager`.
+    pub fn advance(&self, delta: i16) {
+        self.0.set(self.0.get().wrapping_add(delta as u16));
+    }
+}
+
+impl Qei for MockQei {
+    type Count = u16;
+
+    fn count(&self) -> u16 {
+        self.0.get()
+    }
+
+    fn direction(&self) -> Direction {
+        Direction::Upcounting
+    }
+}
@@ -2,3 +2,6 @@ pub mod lrtimer;
 /// Board support library for the WheelTec STM32F103VET6 robot controller.
 pub mod motion;
 pub mod sr04;
+
+#[cfg(test)]
+mod mock;
@@ -1,12 +1,49 @@
 /// Board motion control capabilities.
-use embedded_hal::{digital::v2::OutputPin, Pwm, Qei};
-use fixed::FixedI32;
-// Micromath is acceptable for the operations performed in this module.
-#[allow(unused_imports)]
-use micromath::F32Ext as _;
+use super::lrtimer::Instant;
+use core::{
+    convert::TryFrom,
+    ops::{Index, IndexMut},
+};
+use embedded_hal::{blocking::delay::DelayMs, digital::v2::OutputPin, Pwm, Qei};
+use embedded_time::duration::{Microseconds, Milliseconds};
+use fixed::{
+    types::{I32F32, I8F24},
+    FixedI32,
+};
+use fixed_macro::types::{I17F15 as q17_15, I8F24 as i8f24};
+use heapless::Vec;
 use qei::QeiManager;
 use stm32f1xx_hal::{pwm::Channel, time::Hertz};
 
+#[cfg(not(any(feature = "micromath", feature = "libm")))]
+compile_error!("enable either the `micromath` or `libm` feature");
+
+/// Floating point operations, backed by `libm`.
+#[cfg(feature = "libm")]
+mod float {
+    pub use libm::{ceilf as ceil, floorf as floor, roundf as round};
+}
+
+/// Floating point operations, backed by `micromath`.
+///
+/// Micromath is acceptable for the operations performed in this module.
+#[cfg(all(feature = "micromath", not(feature = "libm")))]
+mod float {
+    use micromath::F32Ext;
+
+    pub fn ceil(x: f32) -> f32 {
+        x.ceil()
+    }
+
+    pub fn floor(x: f32) -> f32 {
+        x.floor()
+    }
+
+    pub fn round(x: f32) -> f32 {
+        x.round()
+    }
+}
+
 /// Q17_15 fixed point type.
 pub type Q17_15 = FixedI32<fixed::types::extra::U15>;
 
@@ -23,12 +60,111 @@ pub type Angle = Q17_15;
 /// 1: Full forward.
 pub type Duty = Q17_15;
 
+/// Controller gain.
+pub type Gain = I8F24;
+
+/// Physical distance.
+///
+/// In units of meters.
+pub type TravelDistance = Q17_15;
+
+/// Electric potential.
+///
+/// In units of volts.
+pub type Voltage = Q17_15;
+
+/// Physical linear speed.
+///
+/// In units of meters per second.
+pub type Speed = Q17_15;
+
+/// Physical angular speed.
+///
+/// In units of radians per second. Positive values turn counter-clockwise
+/// (to the left) when viewed from above.
+pub type AngularSpeed = Q17_15;
+
+/// Physical linear acceleration.
+///
+/// In units of meters per second squared.
+pub type Acceleration = Q17_15;
+
+/// State that the steering servo is left in when `Steering` is dropped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SteeringSafeState {
+    /// Drive the servo to its neutral position.
+    Neutral,
+    /// Disable the PWM channel, sending no signal to the servo.
+    Idle,
+}
+
+/// Error returned when a servo's pulse widths cannot be generated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PulseRangeError {
+    /// The neutral pulse width does not lie between the limits.
+    NeutralOutOfRange,
+    /// A pulse width is not shorter than the PWM period.
+    ExceedsPeriod,
+    /// The limits round to the same duty cycle (or cross over), leaving the
+    /// servo no range of motion.
+    TooNarrow,
+}
+
+/// Error returned when wheel speeds cannot be commanded in meters per second.
+#[derive(Debug)]
+pub enum DriveError {
+    /// Odometry has not been configured, so speeds cannot be converted into
+    /// encoder counts (see `Wheels::set_odometry()`).
+    NoOdometry,
+    /// A requested speed exceeds the configured maximum speed, or does not
+    /// fit in encoder counts per second.
+    Unreachable,
+    /// The encoders could not be sampled.
+    Sampling(qei::SamplingError),
+}
+
+impl From<qei::SamplingError> for DriveError {
+    fn from(error: qei::SamplingError) -> Self {
+        Self::Sampling(error)
+    }
+}
+
 /// Models the vehicle's steering (backed by a TD8120MG servo).
+///
+/// The servo is put in a safe state (see `set_safe_state()`) when this is
+/// dropped. Use `into_parts()` to reclaim the PWM generator without doing so.
 pub struct Steering<T: Pwm> {
-    pwm: T,
-    channel: T::Channel,
+    /// PWM output driving the servo, only taken by `into_parts()`.
+    output: Option<ServoOutput<T>>,
     min_duty: T::Duty,
     max_duty: T::Duty,
+    /// Whether the sign of commanded angles is flipped.
+    inverted: bool,
+    /// Offset added to angles after inversion, shifting the neutral position.
+    trim: Angle,
+    /// Largest change in angle per `set()`, if limited.
+    max_slew: Option<Angle>,
+    /// Last commanded angle.
+    angle: Angle,
+    /// Last angle sent to the servo, after inversion, trim and clamping.
+    applied: Angle,
+    /// Whether the PWM channel has been disabled by `relax()`.
+    relaxed: bool,
+    /// Physical steering angle corresponding to an `Angle` of `1`, in degrees.
+    full_scale_degrees: Q17_15,
+    /// State to leave the servo in when dropped.
+    safe_state: SteeringSafeState,
+    /// Time taken by the servo to travel across its full range.
+    travel_time: Milliseconds<u32>,
+    /// Time at which the last timed angle change was commanded, and the time
+    /// needed for the servo to settle after it.
+    settling: Option<(Instant, Milliseconds<u32>)>,
+}
+
+/// PWM output driving a servo.
+struct ServoOutput<T: Pwm> {
+    pwm: T,
+    channel: T::Channel,
     neutral_duty: T::Duty,
 }
 
@@ -41,67 +177,452 @@ impl<T: Pwm<Time = Hertz>> Steering<T> {
     ///
     /// 200 Hz should be doable for a digital servo.
     const FREQUENCY: Hertz = Hertz(200);
+
+    /// Default physical steering angle corresponding to an `Angle` of `1`,
+    /// in degrees.
+    pub const DEFAULT_FULL_SCALE_DEGREES: Q17_15 = q17_15!(25);
+
+    /// Default time taken by the servo to travel across its full range.
+    ///
+    /// The TD8120MG is rated at roughly 0.16 s / 60 degrees, across a range
+    /// of roughly 180 degrees.
+    pub const DEFAULT_TRAVEL_TIME: Milliseconds<u32> = Milliseconds(480);
+
+    /// TD8120MG pulse width at the lower limit, in microseconds.
+    pub const DEFAULT_MIN_PULSE_US: u32 = 500;
+
+    /// TD8120MG pulse width at the upper limit, in microseconds.
+    pub const DEFAULT_MAX_PULSE_US: u32 = 2500;
+
+    /// TD8120MG pulse width at the neutral position, in microseconds.
+    pub const DEFAULT_NEUTRAL_PULSE_US: u32 = 1500;
 }
 
 impl<T: Pwm<Channel = Channel, Duty = u16, Time = Hertz>> Steering<T> {
-    /// Creates a new servo driver backed by a PWM generator.
+    /// Creates a new servo driver backed by a PWM generator, for a
+    /// TD8120MG servo.
     ///
     /// Also resets the servo to its neutral position.
-    pub fn new(mut pwm: T, channel: T::Channel) -> Self {
+    ///
+    /// Panics if the PWM resolution is too coarse to generate the TD8120MG's
+    /// pulse widths.
+    pub fn new(pwm: T, channel: T::Channel) -> Self {
+        Self::with_pulse_range(
+            pwm,
+            channel,
+            Self::DEFAULT_MIN_PULSE_US,
+            Self::DEFAULT_MAX_PULSE_US,
+            Self::DEFAULT_NEUTRAL_PULSE_US,
+        )
+        .unwrap()
+    }
+
+    /// Creates a new servo driver backed by a PWM generator, for a servo
+    /// with the given pulse widths (in microseconds) at its lower limit,
+    /// upper limit and neutral position.
+    ///
+    /// The limits need not be symmetric about `neutral_us`: positive angles
+    /// are scaled between `neutral_us` and `max_us`, and negative angles
+    /// between `neutral_us` and `min_us`. This allows the full travel to be
+    /// used on either side of linkages with asymmetric travel.
+    ///
+    /// The servo is driven at 200 Hz, so pulse widths must be shorter than
+    /// 5000 microseconds.
+    ///
+    /// Also resets the servo to its neutral position. Returns an error without
+    /// touching the PWM channel if `neutral_us` does not lie in
+    /// `[min_us, max_us]`, if `max_us` is not shorter than the PWM period, or
+    /// if the PWM resolution is too coarse to tell `min_us` and `max_us`
+    /// apart.
+    pub fn with_pulse_range(
+        mut pwm: T,
+        channel: T::Channel,
+        min_us: u32,
+        max_us: u32,
+        neutral_us: u32,
+    ) -> Result<Self, PulseRangeError> {
+        if !(min_us..=max_us).contains(&neutral_us) {
+            return Err(PulseRangeError::NeutralOutOfRange);
+        }
+        if max_us >= 1_000_000 / Self::FREQUENCY.0 {
+            return Err(PulseRangeError::ExceedsPeriod);
+        }
+
         let seconds_per_duty: f32 =
             (1.0_f32 / Self::FREQUENCY.0 as f32) / (pwm.get_max_duty() as f32);
-        let min_duty = 500e-6_f32 / seconds_per_duty;
-        let max_duty = 2500e-6_f32 / seconds_per_duty;
-        let neutral_duty = (min_duty + max_duty) / 2.0;
+        let min_duty = (min_us as f32 * 1e-6_f32) / seconds_per_duty;
+        let max_duty = (max_us as f32 * 1e-6_f32) / seconds_per_duty;
+        let neutral_duty = (neutral_us as f32 * 1e-6_f32) / seconds_per_duty;
 
-        let min_duty = min_duty.ceil() as T::Duty;
-        let max_duty = max_duty.floor() as T::Duty;
-        let neutral_duty = neutral_duty.round() as T::Duty;
+        let min_duty = float::ceil(min_duty) as T::Duty;
+        let max_duty = float::floor(max_duty) as T::Duty;
+        if max_duty >= pwm.get_max_duty() {
+            return Err(PulseRangeError::ExceedsPeriod);
+        }
+        if min_duty >= max_duty {
+            return Err(PulseRangeError::TooNarrow);
+        }
+        let neutral_duty = (float::round(neutral_duty) as T::Duty).clamp(min_duty, max_duty);
 
         pwm.disable(channel);
         pwm.set_period(Self::FREQUENCY);
         pwm.set_duty(channel, neutral_duty);
         pwm.enable(channel);
 
-        Self {
-            pwm,
-            channel,
+        Ok(Self {
+            output: Some(ServoOutput {
+                pwm,
+                channel,
+                neutral_duty,
+            }),
             min_duty,
             max_duty,
-            neutral_duty,
+            inverted: false,
+            trim: Angle::from_num(0),
+            max_slew: None,
+            angle: Angle::from_num(0),
+            applied: Angle::from_num(0),
+            relaxed: false,
+            full_scale_degrees: Self::DEFAULT_FULL_SCALE_DEGREES,
+            safe_state: SteeringSafeState::Neutral,
+            travel_time: Self::DEFAULT_TRAVEL_TIME,
+            settling: None,
+        })
+    }
+
+    /// Sets the time taken by the servo to travel across its full range (from
+    /// an `Angle` of `-1` to `1`).
+    ///
+    /// Used to model how long the servo takes to settle after an angle
+    /// change. Defaults to `DEFAULT_TRAVEL_TIME`.
+    pub fn set_travel_time(&mut self, time: Milliseconds<u32>) {
+        self.travel_time = time;
+    }
+
+    /// Drives the servo to the given angle, recording the time at which this
+    /// was commanded so that `is_settled()` can be used.
+    ///
+    /// The servo is assumed to move at a constant speed, derived from the
+    /// configured travel time.
+    pub fn set_at(&mut self, angle: Angle, now: Instant) {
+        let previous = self.applied;
+        let delta = (self.set(angle) - previous).abs();
+        let settle = Angle::saturating_from_num(self.travel_time.0).saturating_mul(delta) / 2;
+
+        self.settling = Some((now, Milliseconds(settle.ceil().to_num())));
+    }
+
+    /// Determines if the servo has (likely) reached the angle last commanded
+    /// through `set_at()`.
+    ///
+    /// This is an estimate: there is no feedback from the servo. Angle
+    /// changes commanded through `set()` are not taken into account.
+    pub fn is_settled(&self, now: Instant) -> bool {
+        match self.settling {
+            Some((at, settle)) => now
+                .checked_duration_since(&at)
+                .and_then(|elapsed| Milliseconds::<u32>::try_from(elapsed).ok())
+                .map_or(false, |elapsed| elapsed >= settle),
+            None => true,
+        }
+    }
+
+    /// Obtain the frequency at which the servo is actually being driven.
+    ///
+    /// This is the period reported by the HAL, which is derived from the
+    /// timer's prescaler and reload values and may differ slightly from the
+    /// requested frequency due to rounding. The accuracy of this value is
+    /// only as good as the HAL's `get_period()` implementation.
+    pub fn frequency(&self) -> Hertz {
+        match self.output.as_ref() {
+            Some(output) => output.pwm.get_period(),
+            None => unreachable!(),
+        }
+    }
+
+    /// Obtain the difference between the actual and requested servo drive
+    /// frequencies, in Hertz.
+    ///
+    /// Servo pulse widths are scaled by the ratio between the requested and
+    /// actual frequencies.
+    pub fn frequency_error(&self) -> i32 {
+        self.frequency().0 as i32 - Self::FREQUENCY.0 as i32
+    }
+
+    /// Sets the state the servo is left in when this is dropped.
+    ///
+    /// Defaults to `SteeringSafeState::Neutral`.
+    pub fn set_safe_state(&mut self, state: SteeringSafeState) {
+        self.safe_state = state;
+    }
+
+    /// Releases the PWM generator and channel driving the servo.
+    ///
+    /// The servo is left as-is, without being put in its safe state.
+    pub fn into_parts(mut self) -> (T, T::Channel) {
+        // Dropping `self` without its output does not touch the servo.
+        match self.output.take() {
+            Some(output) => (output.pwm, output.channel),
+            None => unreachable!(),
+        }
+    }
+
+    /// Obtain the PWM output driving the servo.
+    fn output(&mut self) -> &mut ServoOutput<T> {
+        // The output is only taken by `into_parts()`, which consumes `self`.
+        match self.output.as_mut() {
+            Some(output) => output,
+            None => unreachable!(),
         }
     }
 
+    /// Sets whether the sign of commanded angles should be flipped.
+    ///
+    /// Allows callers to use a consistent sign convention regardless of how
+    /// the servo is mounted. Angles are not inverted by default.
+    pub fn set_angle_inverted(&mut self, inverted: bool) {
+        self.inverted = inverted;
+    }
+
+    /// Sets an offset shifting the servo's neutral position, to compensate
+    /// for steering linkages that are not centered.
+    ///
+    /// The trim is added to angles after they are inverted (see
+    /// `set_angle_inverted()`), i.e. it is in the servo's own sign
+    /// convention. Trimmed angles are clamped to `[-1, 1]`, so the servo is
+    /// never driven past its limits. Takes effect on the next `set()`.
+    ///
+    /// Defaults to zero.
+    pub fn set_trim(&mut self, trim: Angle) {
+        let limit = Angle::from_num(1);
+        self.trim = trim.clamp(-limit, limit);
+    }
+
+    /// Limits the change in angle made by each `set()` to at most
+    /// `per_call`, so that large jumps are spread across several calls.
+    ///
+    /// A `per_call` of zero or less disables the limit, which is the default.
+    pub fn set_max_slew(&mut self, per_call: Angle) {
+        self.max_slew = if per_call > 0 { Some(per_call) } else { None };
+    }
+
+    /// Sets the physical steering angle (in degrees) reached at either end
+    /// of the servo's range, i.e. at an `Angle` of `1` or `-1`.
+    ///
+    /// Depends on the steering geometry of the vehicle.
+    ///
+    /// Panics if `degrees` is not positive.
+    pub fn set_full_scale_degrees(&mut self, degrees: Q17_15) {
+        assert!(degrees > 0);
+        self.full_scale_degrees = degrees;
+    }
+
+    /// Drives the servo to the given physical steering angle, in degrees.
+    ///
+    /// Angles beyond the full-scale steering angle are clamped.
+    pub fn set_degrees(&mut self, degrees: Q17_15) {
+        let limit = self.full_scale_degrees;
+        self.set(degrees.clamp(-limit, limit) / limit);
+    }
+
+    /// Converts a physical steering angle in radians to an `Angle`, based on
+    /// the full-scale steering angle (see `set_full_scale_degrees()`).
+    ///
+    /// Angles beyond the full-scale steering angle are saturated.
+    pub fn angle_from_radians(&self, radians: Q17_15) -> Angle {
+        let limit = self.full_scale_degrees;
+        radians
+            .saturating_mul(q17_15!(57.29578))
+            .clamp(-limit, limit)
+            / limit
+    }
+
+    /// Drives the servo to the given physical steering angle, in radians.
+    ///
+    /// Angles beyond the full-scale steering angle are clamped.
+    pub fn set_radians(&mut self, radians: Q17_15) {
+        self.set(self.angle_from_radians(radians));
+    }
+
+    /// Obtain the last commanded physical steering angle, in degrees.
+    pub fn degrees(&self) -> Q17_15 {
+        self.angle * self.full_scale_degrees
+    }
+
+    /// Obtain the angle last sent to the servo.
+    ///
+    /// This is the angle after slew limiting, inversion, trim and clamping,
+    /// which is what determines the servo's position, and is also what
+    /// `set()` returns. It is not the angle last passed to `set()`: see
+    /// `degrees()` for that.
+    pub fn angle(&self) -> Angle {
+        self.applied
+    }
+
     /// Drives the servo to the given angle.
-    pub fn set(&mut self, angle: Angle) {
-        if angle > 0_i16 {
-            // FIXME: remove after checking.
-            self.pwm.set_duty(
-                self.channel,
-                (Angle::from(self.max_duty - self.neutral_duty) * angle.abs())
-                    .checked_to_num::<T::Duty>()
-                    .unwrap()
-                    + self.neutral_duty,
-            )
+    ///
+    /// Angles outside `[-1, 1]` are saturated to the nearest limit. If slew
+    /// limiting is enabled (see `set_max_slew()`), the servo is only
+    /// moved towards `angle`. Returns the angle sent to the servo, as
+    /// subsequently returned by `angle()`.
+    pub fn set(&mut self, angle: Angle) -> Angle {
+        let limit = Angle::from_num(1);
+        let angle = angle.clamp(-limit, limit);
+        let angle = match self.max_slew {
+            Some(step) => angle.clamp(
+                self.angle.saturating_sub(step),
+                self.angle.saturating_add(step),
+            ),
+            None => angle,
+        };
+        self.angle = angle;
+        let angle = if self.inverted { -angle } else { angle };
+        self.applied = angle.saturating_add(self.trim).clamp(-limit, limit);
+        self.write();
+
+        self.applied
+    }
+
+    /// Sets the PWM duty cycle for the angle last sent to the servo.
+    fn write(&mut self) {
+        let (angle, min_duty, max_duty) = (self.applied, self.min_duty, self.max_duty);
+        let output = self.output();
+        let neutral_duty = output.neutral_duty;
+        // `angle` lies in `[-1, 1]`, so the offsets from the neutral duty
+        // cannot exceed the distance to either limit.
+        let duty = if angle > 0_i16 {
+            (Angle::from(max_duty - neutral_duty) * angle.abs()).saturating_to_num::<T::Duty>()
+                + neutral_duty
         } else {
-            self.pwm.set_duty(
-                self.channel,
-                self.neutral_duty
-                    - ((Angle::from(self.neutral_duty - self.min_duty) * angle.abs())
-                        .checked_to_num::<T::Duty>()
-                        .unwrap()),
-            )
+            neutral_duty
+                - (Angle::from(neutral_duty - min_duty) * angle.abs())
+                    .saturating_to_num::<T::Duty>()
+        };
+        output.pwm.set_duty(output.channel, duty)
+    }
+
+    /// Lets the servo go limp by disabling its PWM channel.
+    ///
+    /// Saves power and stops the servo from buzzing when steering need not
+    /// be held. Angles commanded while relaxed only take effect after
+    /// `engage()`.
+    pub fn relax(&mut self) {
+        let output = self.output();
+        output.pwm.disable(output.channel);
+        self.relaxed = true;
+    }
+
+    /// Re-enables the PWM channel after `relax()`, driving the servo to the
+    /// last angle sent to it.
+    ///
+    /// Does nothing if the servo is not relaxed.
+    pub fn engage(&mut self) {
+        if self.relaxed {
+            self.write();
+            let output = self.output();
+            output.pwm.enable(output.channel);
+            self.relaxed = false;
         }
     }
 
+    /// Determines if the servo has been relaxed by `relax()`.
+    pub fn is_relaxed(&self) -> bool {
+        self.relaxed
+    }
+
     /// Idles the servo.
     ///
     /// Sends no signal to the servo until another `set()` is called.
     pub fn idle(&mut self) {
-        self.pwm.set_duty(self.channel, 0)
+        let output = self.output();
+        output.pwm.set_duty(output.channel, 0)
+    }
+}
+
+impl<T: Pwm> Drop for Steering<T> {
+    /// Puts the servo in its safe state, unless released by `into_parts()`.
+    fn drop(&mut self) {
+        if let Some(ServoOutput {
+            mut pwm,
+            channel,
+            neutral_duty,
+        }) = self.output.take()
+        {
+            match self.safe_state {
+                SteeringSafeState::Neutral => pwm.set_duty(channel, neutral_duty),
+                SteeringSafeState::Idle => pwm.disable(channel),
+            }
+        }
+    }
+}
+
+/// Generates a steering angle sweeping back and forth between two limits.
+///
+/// Useful for servo characterization and demonstrations: call `step()`
+/// periodically and pass the result to `Steering::set()`.
+pub struct SteeringSweep {
+    /// Lower sweep limit.
+    min: Angle,
+    /// Upper sweep limit.
+    max: Angle,
+    /// Change in angle per step.
+    rate: Angle,
+    /// Current angle.
+    angle: Angle,
+    /// Whether the angle is currently increasing.
+    rising: bool,
+}
+
+impl SteeringSweep {
+    /// Creates a new sweep between `min` and `max`, changing by `rate` every
+    /// step.
+    ///
+    /// The limits are clamped to the valid steering range of `[-1, 1]`. The
+    /// sweep starts at `min`, moving towards `max`.
+    pub fn new(min: Angle, max: Angle, rate: Angle) -> Self {
+        let limit = Angle::from_num(1);
+        let min = min.clamp(-limit, limit);
+        let max = max.clamp(-limit, limit);
+        let (min, max) = if min <= max { (min, max) } else { (max, min) };
+
+        Self {
+            min,
+            max,
+            rate: rate.abs(),
+            angle: min,
+            rising: true,
+        }
+    }
+
+    /// Advances the sweep, returning the next angle.
+    ///
+    /// The sweep reverses direction upon reaching either limit.
+    pub fn step(&mut self) -> Angle {
+        if self.rising {
+            self.angle = self.angle.saturating_add(self.rate).min(self.max);
+            if self.angle == self.max {
+                self.rising = false;
+            }
+        } else {
+            self.angle = self.angle.saturating_sub(self.rate).max(self.min);
+            if self.angle == self.min {
+                self.rising = true;
+            }
+        }
+
+        self.angle
     }
 }
 
+/// Ways in which a motor can be stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopMode {
+    /// Actively brake the motor.
+    Brake,
+    /// Let the motor coast.
+    Coast,
+}
+
 /// Structure modelling a set of `TB6612FNG` control pins.
 ///
 /// Assumes that pin I/O operations never fail.
@@ -143,6 +664,80 @@ impl<P: OutputPin> TB6612FNGControlPins<P> {
         self.in1.set_low().ok();
         self.in2.set_low().ok();
     }
+
+    /// Commands the driver to stop the motor in the given manner.
+    fn stop(&mut self, mode: StopMode) {
+        match mode {
+            StopMode::Brake => self.brake(),
+            StopMode::Coast => self.coast(),
+        }
+    }
+
+    /// Releases the control pins as an array `[in1, in2]`.
+    fn release(self) -> [P; 2] {
+        [self.in1, self.in2]
+    }
+}
+
+/// Control pins of a set of motors, which stops the motors in a configurable
+/// manner when dropped.
+struct MotorPins<P: OutputPin, const N: usize> {
+    /// Control pins of each motor, only taken by `release()`.
+    pins: Option<[TB6612FNGControlPins<P>; N]>,
+    /// Manner in which the motors are stopped when dropped.
+    safe_state: StopMode,
+}
+
+impl<P: OutputPin, const N: usize> MotorPins<P, N> {
+    /// Creates a new set of control pins from an array of `[in1, in2]` pins.
+    fn new(ins: [[P; 2]; N]) -> Self {
+        Self {
+            pins: Some(ins.map(TB6612FNGControlPins::new)),
+            safe_state: StopMode::Brake,
+        }
+    }
+
+    /// Releases the control pins as an array of `[in1, in2]` pins, without
+    /// stopping the motors.
+    fn release(mut self) -> [[P; 2]; N] {
+        match self.pins.take() {
+            Some(pins) => pins.map(TB6612FNGControlPins::release),
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<P: OutputPin, const N: usize> Index<usize> for MotorPins<P, N> {
+    type Output = TB6612FNGControlPins<P>;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        // The pins are only taken by `release()`, which consumes `self`.
+        match self.pins.as_ref() {
+            Some(pins) => &pins[index],
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<P: OutputPin, const N: usize> IndexMut<usize> for MotorPins<P, N> {
+    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
+        match self.pins.as_mut() {
+            Some(pins) => &mut pins[index],
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<P: OutputPin, const N: usize> Drop for MotorPins<P, N> {
+    /// Stops all motors in the configured manner, unless released.
+    fn drop(&mut self) {
+        let mode = self.safe_state;
+        if let Some(pins) = self.pins.as_mut() {
+            for control in pins.iter_mut() {
+                control.stop(mode);
+            }
+        }
+    }
 }
 
 /// Enumeration across all the wheels of the chassis.
@@ -157,18 +752,129 @@ pub enum Wheel {
 
 impl Wheel {
     /// Obtain the index of the wheel.
+    ///
+    /// Index `0` is always the left wheel.
     pub fn index(self) -> usize {
         self as usize
     }
 }
 
+/// Outcome of an encoder polarity check.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Polarity {
+    /// The encoder counts in the same direction as the motor is driven.
+    Correct,
+    /// The encoder counts in the opposite direction to the motor.
+    Inverted,
+    /// The encoder count did not change.
+    NoMotion,
+}
+
+/// Direction of rotation of a wheel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Direction {
+    /// The encoder count is increasing.
+    Forward,
+    /// The encoder count is decreasing.
+    Reverse,
+    /// The encoder count is unchanged.
+    Stopped,
+}
+
+/// Wheel velocity, split into a speed and a direction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SignedVelocity {
+    /// Speed, in encoder counts per second.
+    pub speed: u32,
+    /// Direction of rotation.
+    pub direction: Direction,
+}
+
+/// Parameters used to detect stalled motors (see
+/// `Wheels::set_stall_detection()`).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StallDetection {
+    /// Smallest commanded duty cycle magnitude at which a motor is expected
+    /// to turn.
+    pub min_duty: Duty,
+    /// Largest change in encoder counts still considered to be no motion.
+    pub epsilon: u32,
+    /// Number of consecutive position samples without motion after which a
+    /// motor is considered stalled.
+    pub window: u32,
+}
+
+/// Resources used to drive and sense a single motor.
+pub struct MotorResources<P, C, Q> {
+    /// TB6612FNG control pins, as an array of `[inA, inB]`.
+    pub ins: [P; 2],
+    /// PWM channel driving the motor.
+    pub channel: C,
+    /// Quadrature encoder attached to the motor.
+    pub encoder: Q,
+}
+
 /// Models the TB6612FNG drive motors and encoders.
+///
+/// Both motors are stopped (see `set_safe_state()`) when this is dropped.
+/// Use `into_parts()` to reclaim the underlying peripherals without doing so.
 pub struct Wheels<T: Pwm, Q1: Qei, Q2: Qei, P: OutputPin> {
     pwm: T,
-    ins: [TB6612FNGControlPins<P>; 2],
+    ins: MotorPins<P, 2>,
     channels: [T::Channel; 2],
     encoders: (QeiManager<Q1>, QeiManager<Q2>),
     max_duty: Duty,
+    /// Whether the direction of each encoder is flipped.
+    inverted: [bool; 2],
+    /// Accumulated encoder counts at the origin set by `reset_positions()`.
+    origin: [i64; 2],
+    /// Positions at the previous velocity sample.
+    previous_positions: Option<[i64; 2]>,
+    /// Distance traveled by a wheel per encoder count, in meters.
+    meters_per_count: I32F32,
+    /// Last duty cycle commanded for each wheel.
+    duties: [Duty; 2],
+    /// Largest change in duty cycle per `drive()`, if limited.
+    max_accel: Option<Duty>,
+    /// Smallest duty cycle at which each motor turns.
+    deadbands: [Duty; 2],
+    /// Factor applied to the duty cycle of each motor.
+    trims: [Duty; 2],
+    /// Adaptation rate and largest magnitude of the learned trim, if enabled.
+    adaptive_trim: Option<(Gain, Duty)>,
+    /// Fraction by which the left motor's duty cycle is reduced and the right
+    /// one's increased, as learned while driving straight.
+    learned_trim: Duty,
+    /// Largest duty cycle magnitude sent to either motor.
+    duty_limit: Duty,
+    /// Last measured supply voltage, if compensation is enabled.
+    supply_voltage: Option<Voltage>,
+    /// Supply voltage at which duty cycles are applied as commanded.
+    nominal_voltage: Voltage,
+    /// Distance between the contact points of the left and right wheels, in
+    /// meters.
+    track_width: TravelDistance,
+    /// Stall detection parameters, if enabled.
+    stall_detection: Option<StallDetection>,
+    /// Position of each wheel when it was last seen moving.
+    stall_anchors: [i64; 2],
+    /// Positions at the previous odometer sample.
+    odometer_positions: [i64; 2],
+    /// Sum of the absolute changes in the sum of both wheels' positions, in
+    /// encoder counts, i.e. twice the path length traveled.
+    odometer_counts: u64,
+    /// Number of consecutive samples for which each wheel has not moved while
+    /// driven.
+    stall_samples: [u32; 2],
+    /// Duty cycle and starting positions of the straight-line drive started
+    /// by `drive_straight()`, if any.
+    straight: Option<(Duty, [i64; 2])>,
+    /// Proportional gain used to keep the wheels matched when driving
+    /// straight, in duty cycle per encoder count.
+    straight_gain: Gain,
+    /// Target distance, duty cycle and starting positions of the drive
+    /// started by `drive_distance()`, if any.
+    distance_drive: Option<(TravelDistance, Duty, [i64; 2])>,
 }
 
 impl<
@@ -178,11 +884,43 @@ impl<
         P: OutputPin,
     > Wheels<T, Q1, Q2, P>
 {
+    /// Default gain used to keep the wheels matched when driving straight,
+    /// in duty cycle per encoder count.
+    pub const DEFAULT_STRAIGHT_GAIN: Gain = i8f24!(0.002);
+
+    /// Default supply voltage at which duty cycles are applied as commanded,
+    /// that of a 2S LiPo battery.
+    pub const DEFAULT_NOMINAL_VOLTAGE: Voltage = q17_15!(7.4);
+
+    /// Instantiates a new `Wheels` representation from the resources of each
+    /// side of the robot.
+    ///
+    /// This is the recommended way to construct `Wheels`, as it leaves no
+    /// room to mix up the left and right sides.
+    ///
+    /// The motors are left in the braked state after this function returns.
+    pub fn from_sides(
+        pwm: T,
+        period: T::Time,
+        left: MotorResources<P, T::Channel, Q1>,
+        right: MotorResources<P, T::Channel, Q2>,
+    ) -> Self {
+        Self::new(
+            pwm,
+            period,
+            [left.ins, right.ins],
+            [left.channel, right.channel],
+            (left.encoder, right.encoder),
+        )
+    }
+
     /// Instantiates a new `Wheels` representation.
     ///
     /// `ins` is an array of  `[inA, inB]` pins.
     /// The same goes for all the other arrays.
-    /// Index `0` must correspond to resources on the left side of the robot.
+    ///
+    /// **Index `0` must correspond to resources on the left side of the
+    /// robot.** Prefer `from_sides()`, which makes this explicit.
     ///
     /// The motors are left in the braked state after this function returns.
     pub fn new(
@@ -198,19 +936,37 @@ impl<
         pwm.enable(channels[0]);
         pwm.enable(channels[1]);
 
-        let [insl, insr] = ins;
         let (encl, encr) = encoders;
         let max_duty = pwm.get_max_duty().into();
 
         let mut out = Self {
             pwm,
-            ins: [
-                TB6612FNGControlPins::new(insl),
-                TB6612FNGControlPins::new(insr),
-            ],
+            ins: MotorPins::new(ins),
             channels,
             encoders: (QeiManager::new(encl), QeiManager::new(encr)),
             max_duty,
+            inverted: [false; 2],
+            origin: [0; 2],
+            previous_positions: None,
+            meters_per_count: I32F32::from_num(0),
+            duties: [Duty::from_num(0); 2],
+            max_accel: None,
+            deadbands: [Duty::from_num(0); 2],
+            trims: [Duty::from_num(1); 2],
+            adaptive_trim: None,
+            learned_trim: Duty::from_num(0),
+            duty_limit: Duty::from_num(1),
+            supply_voltage: None,
+            nominal_voltage: Self::DEFAULT_NOMINAL_VOLTAGE,
+            track_width: TravelDistance::from_num(0),
+            stall_detection: None,
+            stall_anchors: [0; 2],
+            odometer_positions: [0; 2],
+            odometer_counts: 0,
+            stall_samples: [0; 2],
+            straight: None,
+            straight_gain: Self::DEFAULT_STRAIGHT_GAIN,
+            distance_drive: None,
         };
 
         out.drive(Wheel::LEFT, 0_u16.into());
@@ -223,54 +979,2056 @@ impl<
         self.pwm.get_max_duty()
     }
 
-    /// Command a motor to coast.
-    pub fn coast(&mut self, which: Wheel) {
-        self.ins[which.index()].coast()
+    /// Obtain the PWM period actually configured on the timer.
+    ///
+    /// This is the period reported by the HAL, and may differ slightly from
+    /// the period passed to `new()` due to prescaler rounding.
+    pub fn period(&self) -> T::Time {
+        self.pwm.get_period()
     }
 
-    /// Command a motor to be driven in a given direction at a provided
-    /// duty cycle.
+    /// Obtain the effective resolution of duty cycle commands, in whole bits.
     ///
-    /// If `duty == 0`, the motor is actively braked.
-    pub fn drive(&mut self, which: Wheel, duty: Duty) {
-        let control = &mut self.ins[which.index()];
-        if duty != 0 {
-            if duty > 0 {
-                control.cw();
-            } else {
-                control.ccw();
-            }
-        } else {
-            control.brake();
-        }
+    /// This is limited both by the PWM resolution and by the precision of
+    /// `Duty`. Duty cycles smaller than `2^-bits` may be quantized to zero,
+    /// so a low value here means that low-speed commands may not move the
+    /// motors at all.
+    pub fn duty_resolution_bits(&self) -> u32 {
+        let steps = u32::from(self.resolution()) + 1;
+        (31 - steps.leading_zeros()).min(Duty::FRAC_NBITS)
+    }
 
-        self.pwm.set_duty(
-            self.channels[which.index()],
-            (duty.abs() * self.max_duty).checked_to_num().unwrap(),
-        );
+    /// Configures odometry for wheels of radius `wheel_radius` (in meters),
+    /// with encoders reporting `counts_per_rev` counts per wheel revolution.
+    ///
+    /// Distances are reported as zero until this is called.
+    ///
+    /// Panics if `wheel_radius` is not positive or `counts_per_rev` is zero.
+    pub fn set_odometry(&mut self, wheel_radius: TravelDistance, counts_per_rev: u32) {
+        assert!(wheel_radius > 0 && counts_per_rev > 0);
+        self.meters_per_count =
+            I32F32::from_num(wheel_radius) * I32F32::from_num(fixed::consts::TAU) / counts_per_rev;
     }
 
-    /// Reads the positions of both motors' output shafts, while updating the
-    /// internal position counter to deal with hardware encoder counter
-    /// overflow.
+    /// Obtain the distance traveled by each wheel, in meters, based on the
+    /// positions cached by the last `read_and_update_positions()`.
     ///
-    /// The position is given in terms of encoder counts.
+    /// Computed from the accumulated encoder counts, so no error builds up
+    /// over time. Saturates for distances that cannot be represented.
     ///
-    /// `[0]` is the position of the left motor's shaft and `[1]` is the
-    /// position of the right one.
+    /// `[0]` is the distance traveled by the left wheel and `[1]` is the
+    /// distance traveled by the right one.
+    pub fn distance_traveled(&self) -> [TravelDistance; 2] {
+        self.read_positions()
+            .map(|count| self.counts_to_meters(count))
+    }
+
+    /// Obtain the signed distance remaining for each wheel to reach a target
+    /// position, in meters, based on the positions cached by the last
+    /// `read_and_update_positions()`.
     ///
-    /// Must be called periodically to avoid sampling errors.
-    pub fn read_and_update_positions(&mut self) -> Result<[i64; 2], qei::SamplingError> {
-        self.encoders
-            .0
-            .sample()
-            .and_then(|_| self.encoders.1.sample())
-            .map(|_| self.read_positions())
+    /// `target` is given in terms of encoder counts, as with
+    /// `remaining_to_target()`. A negative remaining distance means that the
+    /// wheel has already overshot the target. Uses the configured odometry
+    /// (see `set_odometry()`), and saturates for distances that cannot be
+    /// represented.
+    pub fn remaining_distance(&self, target: [i64; 2]) -> [TravelDistance; 2] {
+        remaining_to_target(self.read_positions(), target).map(|count| self.counts_to_meters(count))
     }
 
-    /// Does the same as `read_and_update_positions`, except that the last
-    /// cached value is output instead.
-    pub fn read_positions(&self) -> [i64; 2] {
-        [self.encoders.0.count(), self.encoders.1.count()]
+    /// Sets the distance between the contact points of the left and right
+    /// wheels, in meters.
+    ///
+    /// Used to convert angular speeds into wheel speeds. Panics if
+    /// `track_width` is not positive.
+    pub fn set_track_width(&mut self, track_width: TravelDistance) {
+        assert!(track_width > 0);
+        self.track_width = track_width;
+    }
+
+    /// Obtain the distance between the contact points of the left and right
+    /// wheels, in meters, or zero if it has not been set.
+    pub fn track_width(&self) -> TravelDistance {
+        self.track_width
+    }
+
+    /// Converts a wheel speed, in meters per second, into encoder counts per
+    /// second.
+    ///
+    /// Returns zero if odometry has not been configured (see
+    /// `set_odometry()`).
+    pub fn speed_to_counts(&self, speed: Speed) -> i32 {
+        if self.meters_per_count == 0 {
+            return 0;
+        }
+
+        I32F32::saturating_from_num(speed)
+            .saturating_div(self.meters_per_count)
+            .saturating_to_num()
+    }
+
+    /// Drives the chassis open loop at a forward speed `v` (in meters per
+    /// second) and yaw rate `omega` (in radians per second).
+    ///
+    /// Wheel speeds are mapped linearly onto duty cycles, `full_speed` being
+    /// the wheel speed reached at full duty. See `differential_kinematics()`
+    /// for the conventions used. Like `drive()`, must be called repeatedly if
+    /// acceleration is limited. Panics if `full_speed` is not positive.
+    pub fn drive_twist(&mut self, v: Speed, omega: AngularSpeed, full_speed: Speed) {
+        assert!(full_speed > 0);
+        let speeds = differential_kinematics(v, omega, self.track_width);
+        for which in [Wheel::LEFT, Wheel::RIGHT] {
+            let duty = speeds[which.index()].saturating_div(full_speed);
+            self.drive(which, duty);
+        }
+    }
+
+    /// Converts a number of encoder counts into the distance traveled by a
+    /// wheel, in meters.
+    fn counts_to_meters(&self, counts: i64) -> TravelDistance {
+        TravelDistance::saturating_from_num(
+            I32F32::saturating_from_num(counts).saturating_mul(self.meters_per_count),
+        )
+    }
+
+    /// Sets the manner in which the motors are stopped when this is dropped.
+    ///
+    /// Defaults to `StopMode::Brake`.
+    pub fn set_safe_state(&mut self, mode: StopMode) {
+        self.ins.safe_state = mode;
+    }
+
+    /// Releases the underlying peripherals, in the same layout as accepted by
+    /// `new()`.
+    ///
+    /// The encoders are returned still wrapped in their `QeiManager`s. The
+    /// motors are left as-is, without being stopped.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        self,
+    ) -> (
+        T,
+        [[P; 2]; 2],
+        [T::Channel; 2],
+        (QeiManager<Q1>, QeiManager<Q2>),
+    ) {
+        (self.pwm, self.ins.release(), self.channels, self.encoders)
+    }
+
+    /// Limits the change in duty cycle made by each `drive()` to at most
+    /// `per_call`, so that the motors accelerate gradually.
+    ///
+    /// When reversing, the duty cycle is ramped down to zero (braking) before
+    /// ramping up in the opposite direction. A `per_call` of zero or less
+    /// disables the limit, which is the default.
+    ///
+    /// With a limit, `drive()` (and everything built on it) must be called
+    /// repeatedly with the same target until it is reached. `stop()` and
+    /// `coast()` are not limited, and should be used to stop the motors.
+    pub fn set_max_accel(&mut self, per_call: Duty) {
+        self.max_accel = if per_call > 0 { Some(per_call) } else { None };
+    }
+
+    /// Sets the smallest duty cycle at which a motor turns.
+    ///
+    /// Non-zero duty cycles passed to `drive()` are then mapped from
+    /// `(0, 1]` onto `(deadband, 1]`, skipping the range in which the motor
+    /// does not turn. A duty cycle of zero still brakes. `deadband` is
+    /// clamped to `[0, 1]`, and defaults to zero.
+    pub fn set_deadband(&mut self, which: Wheel, deadband: Duty) {
+        self.deadbands[which.index()] = deadband.clamp(Duty::from_num(0), Duty::from_num(1));
+    }
+
+    /// Sets a factor by which the duty cycle of a motor is multiplied, to
+    /// correct for differences between the motors (e.g. `0.97` on the
+    /// stronger side to drive straight).
+    ///
+    /// The trimmed duty cycle is still limited to full scale. `factor` is
+    /// clamped to be non-negative, and defaults to `1`.
+    pub fn set_trim(&mut self, which: Wheel, factor: Duty) {
+        self.trims[which.index()] = factor.max(Duty::from_num(0));
+    }
+
+    /// Enables learning a trim between the wheels while driving straight,
+    /// e.g. to follow drift that changes with battery level and wear.
+    ///
+    /// On every `update_straight()`, the learned trim moves by `rate` times
+    /// the difference in encoder counts between the wheels since the start of
+    /// the straight-line drive, and is clamped to `[-limit, limit]`. It is
+    /// applied on top of the trims set through `set_trim()`. A `rate` that is
+    /// not positive stops the learning (the default), but keeps applying the
+    /// trim learned so far. `limit` is clamped to `[0, 1]`.
+    pub fn set_adaptive_trim(&mut self, rate: Gain, limit: Duty) {
+        self.adaptive_trim = if rate > 0 {
+            Some((rate, limit.clamp(Duty::from_num(0), Duty::from_num(1))))
+        } else {
+            None
+        };
+    }
+
+    /// Obtain the trim learned while driving straight (see
+    /// `set_adaptive_trim()`).
+    ///
+    /// The left motor's duty cycle is multiplied by `1 - trim` and the right
+    /// one's by `1 + trim`, so a positive trim slows the left wheel down.
+    pub fn learned_trim(&self) -> Duty {
+        self.learned_trim
+    }
+
+    /// Discards the trim learned while driving straight.
+    pub fn reset_learned_trim(&mut self) {
+        self.learned_trim = Duty::from_num(0);
+    }
+
+    /// Limits the magnitude of the duty cycle sent to either motor to
+    /// `limit`, regardless of what is commanded, e.g. as a safety cap during
+    /// bring-up.
+    ///
+    /// The limit applies after trim, supply voltage and deadband
+    /// compensation. Braking is unaffected. `limit` is clamped to `[0, 1]`,
+    /// and defaults to `1` (no limit).
+    pub fn set_duty_limit(&mut self, limit: Duty) {
+        self.duty_limit = limit.clamp(Duty::from_num(0), Duty::from_num(1));
+    }
+
+    /// Sets the measured motor supply voltage, enabling supply voltage
+    /// compensation.
+    ///
+    /// Duty cycles are then scaled by the ratio of the nominal voltage (see
+    /// `set_nominal_voltage()`) to `voltage`, up to full scale, so that the
+    /// effective motor voltage does not change as the battery discharges.
+    /// Takes effect on the next `drive()`. Passing a voltage of zero or less
+    /// disables compensation, which is the default.
+    pub fn set_supply_voltage(&mut self, voltage: Voltage) {
+        self.supply_voltage = if voltage > 0 { Some(voltage) } else { None };
+    }
+
+    /// Sets the supply voltage at which duty cycles are applied as commanded
+    /// when supply voltage compensation is enabled.
+    ///
+    /// Defaults to `DEFAULT_NOMINAL_VOLTAGE`. Panics if `voltage` is not
+    /// positive.
+    pub fn set_nominal_voltage(&mut self, voltage: Voltage) {
+        assert!(voltage > 0);
+        self.nominal_voltage = voltage;
+    }
+
+    /// Enables stall detection with the given parameters, or disables it if
+    /// `None` is passed (the default).
+    ///
+    /// A motor is considered stalled once it has been driven at a duty cycle
+    /// of at least `min_duty` in magnitude, without its encoder count moving
+    /// by more than `epsilon`, for `window` consecutive calls to
+    /// `read_and_update_positions()`. See `check_stall()`.
+    pub fn set_stall_detection(&mut self, detection: Option<StallDetection>) {
+        self.stall_detection = detection;
+        self.stall_anchors = self.read_positions();
+        self.stall_samples = [0; 2];
+    }
+
+    /// Checks whether a motor has stalled.
+    ///
+    /// Always `false` if stall detection is disabled. The application should
+    /// cut power to a stalled motor to avoid damaging it.
+    pub fn check_stall(&self, which: Wheel) -> bool {
+        self.stall_detection.map_or(false, |detection| {
+            self.stall_samples[which.index()] >= detection.window
+        })
+    }
+
+    /// Updates the stall detection state of both motors with newly sampled
+    /// positions.
+    fn update_stall_detection(&mut self, positions: [i64; 2]) {
+        let detection = match self.stall_detection {
+            Some(detection) => detection,
+            None => return,
+        };
+
+        for i in 0..2 {
+            let moved = (positions[i] - self.stall_anchors[i]).unsigned_abs();
+            if self.duties[i].abs() >= detection.min_duty && moved <= u64::from(detection.epsilon) {
+                self.stall_samples[i] = self.stall_samples[i].saturating_add(1);
+            } else {
+                self.stall_anchors[i] = positions[i];
+                self.stall_samples[i] = 0;
+            }
+        }
+    }
+
+    /// Obtain the last duty cycle commanded for a motor.
+    ///
+    /// This is the signed duty cycle after acceleration limiting, before trim
+    /// and deadband compensation are applied. A coasting motor reports zero.
+    pub fn duty(&self, which: Wheel) -> Duty {
+        self.duties[which.index()]
+    }
+
+    /// Command a motor to coast.
+    ///
+    /// Ends any straight-line drive started by `drive_straight()` or
+    /// `drive_distance()`.
+    pub fn coast(&mut self, which: Wheel) {
+        self.straight = None;
+        self.distance_drive = None;
+        self.duties[which.index()] = Duty::from_num(0);
+        self.ins[which.index()].coast()
+    }
+
+    /// Command a motor to stop in the given manner, with zero duty cycle.
+    ///
+    /// Unlike `drive()`, this takes effect immediately, regardless of any
+    /// acceleration limit. Ends any straight-line drive started by
+    /// `drive_straight()` or `drive_distance()`.
+    pub fn stop(&mut self, which: Wheel, mode: StopMode) {
+        self.straight = None;
+        self.distance_drive = None;
+        self.duties[which.index()] = Duty::from_num(0);
+        self.pwm.set_duty(self.channels[which.index()], 0);
+        self.ins[which.index()].stop(mode);
+    }
+
+    /// Command a motor to be driven in a given direction at a provided
+    /// duty cycle.
+    ///
+    /// If `duty == 0`, the motor is actively braked. `duty` is clamped to
+    /// `[-1, 1]`, and further to the duty limit (see `set_duty_limit()`).
+    ///
+    /// If acceleration limiting is enabled (see `set_max_accel()`), the duty
+    /// cycle is only moved towards `duty`.
+    pub fn drive(&mut self, which: Wheel, duty: Duty) {
+        let duty = duty.clamp(-self.duty_limit, self.duty_limit);
+        let current = self.duties[which.index()];
+        let duty = match self.max_accel {
+            Some(step) => {
+                let next = duty.clamp(current.saturating_sub(step), current.saturating_add(step));
+                if current != 0 && next != 0 && (current > 0) != (next > 0) {
+                    // Ramp through zero instead of jumping across it.
+                    Duty::from_num(0)
+                } else {
+                    next
+                }
+            }
+            None => duty,
+        };
+        self.duties[which.index()] = duty;
+
+        let control = &mut self.ins[which.index()];
+        if duty != 0 {
+            if duty > 0 {
+                control.cw();
+            } else {
+                control.ccw();
+            }
+        } else {
+            control.brake();
+        }
+
+        let magnitude = if duty != 0 {
+            let learned = match which {
+                Wheel::LEFT => Duty::from_num(1).saturating_sub(self.learned_trim),
+                Wheel::RIGHT => Duty::from_num(1).saturating_add(self.learned_trim),
+            };
+            let trimmed = duty
+                .abs()
+                .saturating_mul(self.trims[which.index()])
+                .saturating_mul(learned)
+                .min(Duty::from_num(1));
+            let compensated = match self.supply_voltage {
+                Some(voltage) => trimmed
+                    .saturating_mul(self.nominal_voltage.saturating_div(voltage))
+                    .min(Duty::from_num(1)),
+                None => trimmed,
+            };
+            let deadband = self.deadbands[which.index()];
+            (deadband + compensated * (Duty::from_num(1) - deadband)).min(self.duty_limit)
+        } else {
+            Duty::from_num(0)
+        };
+
+        self.pwm.set_duty(
+            self.channels[which.index()],
+            (magnitude * self.max_duty).saturating_to_num(),
+        );
+    }
+
+    /// Spins the vehicle about its center, driving the wheels at `duty` in
+    /// opposite directions.
+    ///
+    /// Positive duty cycles turn counter-clockwise (to the left) when viewed
+    /// from above, and negative ones clockwise. Trim and the duty limit apply
+    /// as with `drive()`, as does acceleration limiting: call repeatedly until
+    /// the duty cycle is reached. Ends any drive started by `drive_straight()`
+    /// or `drive_distance()`.
+    pub fn turn_in_place(&mut self, duty: Duty) {
+        self.straight = None;
+        self.distance_drive = None;
+        self.drive(Wheel::LEFT, duty.saturating_neg());
+        self.drive(Wheel::RIGHT, duty);
+    }
+
+    /// Sets the proportional gain used to keep the wheels matched when
+    /// driving straight, in duty cycle per encoder count of difference
+    /// between the wheels.
+    ///
+    /// Defaults to `DEFAULT_STRAIGHT_GAIN`.
+    pub fn set_straight_gain(&mut self, gain: Gain) {
+        self.straight_gain = gain;
+    }
+
+    /// Starts driving both wheels at `duty`, keeping them matched so that the
+    /// vehicle drives in a straight line.
+    ///
+    /// The wheels are matched on the distance traveled since this call, using
+    /// the positions cached by the last `read_and_update_positions()`.
+    /// `update_straight()` must then be called periodically. The drive
+    /// continues until `stop()` or `coast()` is called. Ends any drive
+    /// started by `drive_distance()`.
+    pub fn drive_straight(&mut self, duty: Duty) {
+        self.distance_drive = None;
+        self.straight = Some((duty, self.read_positions()));
+        self.drive(Wheel::LEFT, duty);
+        self.drive(Wheel::RIGHT, duty);
+    }
+
+    /// Samples the encoders and corrects the duty cycles of a straight-line
+    /// drive started by `drive_straight()`.
+    ///
+    /// The wheel that is ahead is slowed down and the other sped up, in
+    /// proportion to the difference in their traveled distances. Also updates
+    /// the learned trim, if enabled (see `set_adaptive_trim()`). If no
+    /// straight-line drive is in progress, only samples the encoders.
+    pub fn update_straight(&mut self) -> Result<(), qei::SamplingError> {
+        let positions = self.read_and_update_positions()?;
+        let (duty, start) = match self.straight {
+            Some(straight) => straight,
+            None => return Ok(()),
+        };
+
+        let [left, right] = position_delta(start, positions);
+        let difference = I32F32::saturating_from_num(left.saturating_sub(right));
+        let correction = Duty::saturating_from_num(
+            I32F32::from_num(self.straight_gain).saturating_mul(difference),
+        );
+
+        if let Some((rate, limit)) = self.adaptive_trim {
+            // Compare distances rather than positions so that the trim
+            // learned while reversing has the same meaning.
+            let asymmetry = I32F32::saturating_from_num(
+                left.saturating_abs().saturating_sub(right.saturating_abs()),
+            );
+            let step = Duty::saturating_from_num(I32F32::from_num(rate).saturating_mul(asymmetry));
+            self.learned_trim = self
+                .learned_trim
+                .saturating_add(step)
+                .clamp(limit.saturating_neg(), limit);
+        }
+
+        self.drive(Wheel::LEFT, duty.saturating_sub(correction));
+        self.drive(Wheel::RIGHT, duty.saturating_add(correction));
+        Ok(())
+    }
+
+    /// Starts driving both wheels at `duty` until the vehicle has traveled
+    /// `target` meters, backwards if `target` is negative.
+    ///
+    /// The sign of `duty` is ignored. Distances are measured from the
+    /// positions cached by the last `read_and_update_positions()`, using the
+    /// configured odometry (see `set_odometry()`), without which the target
+    /// is never reached. `update_distance()` must then be called
+    /// periodically.
+    pub fn drive_distance(&mut self, target: TravelDistance, duty: Duty) {
+        let duty = if target < 0 {
+            duty.saturating_abs().saturating_neg()
+        } else {
+            duty.saturating_abs()
+        };
+
+        self.straight = None;
+        self.distance_drive = Some((target, duty, self.read_positions()));
+        self.drive(Wheel::LEFT, duty);
+        self.drive(Wheel::RIGHT, duty);
+    }
+
+    /// Samples the encoders and checks whether a drive started by
+    /// `drive_distance()` has reached its target, braking both wheels if so
+    /// and otherwise re-applying the commanded duty cycle.
+    ///
+    /// The distance traveled is the average of that of both wheels. Motion
+    /// away from the target (e.g. the vehicle being pushed back) must be made
+    /// up before the target is reached.
+    ///
+    /// Returns `true` once the target is reached, as well as if no drive is in
+    /// progress (e.g. because it was ended by `stop()`).
+    pub fn update_distance(&mut self) -> Result<bool, qei::SamplingError> {
+        let positions = self.read_and_update_positions()?;
+        let (target, duty, start) = match self.distance_drive {
+            Some(drive) => drive,
+            None => return Ok(true),
+        };
+
+        let [left, right] = position_delta(start, positions);
+        let average = (i128::from(left) + i128::from(right)) / 2;
+        let traveled = self.counts_to_meters(average as i64);
+        let reached = if target < 0 {
+            traveled <= target
+        } else {
+            traveled >= target
+        };
+
+        if reached {
+            self.stop(Wheel::LEFT, StopMode::Brake);
+            self.stop(Wheel::RIGHT, StopMode::Brake);
+        } else {
+            // Keep ramping towards the duty cycle if acceleration is limited.
+            self.drive(Wheel::LEFT, duty);
+            self.drive(Wheel::RIGHT, duty);
+        }
+
+        Ok(reached)
+    }
+
+    /// Checks that a wheel's encoder counts in the same direction as the motor
+    /// is driven, i.e. that positive duty cycles increase the count.
+    ///
+    /// Drives the wheel at `test_duty` for `ticks` milliseconds, sampling the
+    /// encoders and re-applying the duty cycle (so that any acceleration limit
+    /// is ramped through) every millisecond, and then brakes it immediately
+    /// with `stop()`. Both should be kept
+    /// conservative (e.g. a duty of `0.2` for `100` ticks) since the vehicle
+    /// will move.
+    ///
+    /// This blocks for the duration of the test.
+    pub fn check_encoder_polarity<D: DelayMs<u32>>(
+        &mut self,
+        which: Wheel,
+        test_duty: Duty,
+        ticks: u32,
+        delay: &mut D,
+    ) -> Result<Polarity, qei::SamplingError> {
+        let result = self.read_and_update_positions().and_then(|before| {
+            self.drive(which, test_duty);
+            for _ in 0..ticks {
+                delay.delay_ms(1);
+                self.read_and_update_positions()?;
+                self.drive(which, test_duty);
+            }
+
+            let moved = self.read_positions()[which.index()] - before[which.index()];
+            Ok(if moved == 0 || test_duty == 0 {
+                Polarity::NoMotion
+            } else if (moved > 0) == (test_duty > 0) {
+                Polarity::Correct
+            } else {
+                Polarity::Inverted
+            })
+        });
+
+        self.stop(which, StopMode::Brake);
+        result
+    }
+
+    /// Reads the positions of both motors' output shafts, while updating the
+    /// internal position counter to deal with hardware encoder counter
+    /// overflow.
+    ///
+    /// The position is given in terms of encoder counts.
+    ///
+    /// `[0]` is the position of the left motor's shaft and `[1]` is the
+    /// position of the right one.
+    ///
+    /// Must be called periodically to avoid sampling errors. Also drives
+    /// stall detection (see `set_stall_detection()`).
+    pub fn read_and_update_positions(&mut self) -> Result<[i64; 2], qei::SamplingError> {
+        self.encoders.0.sample()?;
+        self.encoders.1.sample()?;
+
+        let positions = self.read_positions();
+        self.update_stall_detection(positions);
+        let [left, right] = position_delta(self.odometer_positions, positions);
+        self.odometer_positions = positions;
+        self.odometer_counts = self
+            .odometer_counts
+            .saturating_add(left.wrapping_add(right).unsigned_abs());
+        Ok(positions)
+    }
+
+    /// Obtain the total path length traveled by the vehicle since this was
+    /// created, in meters.
+    ///
+    /// Unlike `distance_traveled()`, this never decreases: the distance
+    /// traveled by the center of the vehicle between two samples (see
+    /// `read_and_update_positions()`) is counted regardless of its direction.
+    /// Turning in place does not count. Uses the configured odometry (see
+    /// `set_odometry()`), and saturates instead of wrapping around.
+    pub fn total_distance(&self) -> TravelDistance {
+        self.counts_to_meters(i64::try_from(self.odometer_counts / 2).unwrap_or(i64::MAX))
+    }
+
+    /// Reads the positions of both motors' output shafts, returning the change
+    /// in position since the previous velocity sample.
+    ///
+    /// Returns `[0, 0]` if there is no previous sample.
+    fn sample_deltas(&mut self) -> Result<[i64; 2], qei::SamplingError> {
+        let positions = self.read_and_update_positions()?;
+        Ok(self
+            .previous_positions
+            .replace(positions)
+            .map_or([0, 0], |previous| position_delta(previous, positions)))
+    }
+
+    /// Reads the velocities of both wheels, as a speed and a direction.
+    ///
+    /// `dt` is the time elapsed since the previous velocity sample. Both
+    /// wheels are reported as stopped on the first call, as there is no
+    /// previous sample to compare against.
+    ///
+    /// `[0]` is the velocity of the left wheel and `[1]` is the velocity of
+    /// the right one.
+    pub fn read_signed_velocity(
+        &mut self,
+        dt: Microseconds<u32>,
+    ) -> Result<[SignedVelocity; 2], qei::SamplingError> {
+        let deltas = self.sample_deltas()?;
+        let velocity = |delta: i64| {
+            let speed = counts_per_second(delta, dt)
+                .unsigned_abs()
+                .min(u128::from(u32::MAX)) as u32;
+            let direction = match delta {
+                _ if speed == 0 => Direction::Stopped,
+                d if d > 0 => Direction::Forward,
+                _ => Direction::Reverse,
+            };
+
+            SignedVelocity { speed, direction }
+        };
+
+        Ok([velocity(deltas[0]), velocity(deltas[1])])
+    }
+
+    /// Reads the velocities of both wheels, in encoder counts per second.
+    ///
+    /// `dt` is the time elapsed since the previous velocity sample. Both
+    /// wheels are reported as stopped on the first call, as there is no
+    /// previous sample to compare against, as well as when `dt` is zero.
+    ///
+    /// `[0]` is the velocity of the left wheel and `[1]` is the velocity of
+    /// the right one.
+    pub fn read_velocities(
+        &mut self,
+        dt: Microseconds<u32>,
+    ) -> Result<[i32; 2], qei::SamplingError> {
+        let deltas = self.sample_deltas()?;
+        let velocity = |delta: i64| {
+            counts_per_second(delta, dt).clamp(i128::from(i32::MIN), i128::from(i32::MAX)) as i32
+        };
+
+        Ok([velocity(deltas[0]), velocity(deltas[1])])
+    }
+
+    /// Reads the speeds of both wheels, in meters per second.
+    ///
+    /// Converted from `read_velocities()` using the configured odometry (see
+    /// `set_odometry()`), so speeds are zero until it is configured, as well
+    /// as on the first call. Positive speeds correspond to increasing encoder
+    /// counts, i.e. to the wheel moving forward when encoder polarity is
+    /// correct (see `check_encoder_polarity()`).
+    ///
+    /// `[0]` is the speed of the left wheel and `[1]` is the speed of the
+    /// right one.
+    pub fn read_speeds_mps(
+        &mut self,
+        dt: Microseconds<u32>,
+    ) -> Result<[Speed; 2], qei::SamplingError> {
+        let velocities = self.read_velocities(dt)?;
+        Ok(velocities.map(|velocity| self.counts_to_meters(i64::from(velocity))))
+    }
+
+    /// Does the same as `read_and_update_positions`, except that the last
+    /// cached value is output instead.
+    pub fn read_positions(&self) -> [i64; 2] {
+        position_delta(self.origin, self.accumulated_counts())
+    }
+
+    /// Moves the origin of the position counters to the current positions,
+    /// so that both wheels read as being at position zero.
+    ///
+    /// Uses the positions cached by the last `read_and_update_positions()`,
+    /// so this should be called right after it. Velocity estimates, stall
+    /// detection and any drive started by `drive_straight()` or
+    /// `drive_distance()` are unaffected, so the next sample shows no
+    /// spurious jump.
+    pub fn reset_positions(&mut self) {
+        let shift = self.read_positions();
+        self.origin = self.accumulated_counts();
+        self.previous_positions = self
+            .previous_positions
+            .map(|previous| position_delta(shift, previous));
+        self.stall_anchors = position_delta(shift, self.stall_anchors);
+        self.odometer_positions = position_delta(shift, self.odometer_positions);
+        self.straight = self
+            .straight
+            .map(|(duty, start)| (duty, position_delta(shift, start)));
+        self.distance_drive = self
+            .distance_drive
+            .map(|(target, duty, start)| (target, duty, position_delta(shift, start)));
+    }
+
+    /// Sets whether the direction of a wheel's encoder is flipped, e.g. so
+    /// that both wheels count up when driving forward despite their encoders
+    /// being mounted in opposite directions.
+    ///
+    /// Applies to all positions, velocities and distances reported by this.
+    /// The wheel's current position is negated along with its direction, as
+    /// if the encoder had been flipped all along, so that velocities show no
+    /// spurious jump. Defaults to `false`.
+    pub fn set_invert(&mut self, which: Wheel, inverted: bool) {
+        let i = which.index();
+        if self.inverted[i] != inverted {
+            self.inverted[i] = inverted;
+            self.origin[i] = self.origin[i].wrapping_neg();
+            if let Some(previous) = self.previous_positions.as_mut() {
+                previous[i] = previous[i].wrapping_neg();
+            }
+            self.stall_anchors[i] = self.stall_anchors[i].wrapping_neg();
+            self.odometer_positions[i] = self.odometer_positions[i].wrapping_neg();
+            if let Some((_, start)) = self.straight.as_mut() {
+                start[i] = start[i].wrapping_neg();
+            }
+            if let Some((_, _, start)) = self.distance_drive.as_mut() {
+                start[i] = start[i].wrapping_neg();
+            }
+        }
+    }
+
+    /// Obtain the encoder counts accumulated by the `QeiManager`s, after
+    /// direction inversion but ignoring the origin set by
+    /// `reset_positions()`.
+    fn accumulated_counts(&self) -> [i64; 2] {
+        let counts = [self.encoders.0.count(), self.encoders.1.count()];
+        [0, 1].map(|i| {
+            if self.inverted[i] {
+                counts[i].wrapping_neg()
+            } else {
+                counts[i]
+            }
+        })
+    }
+
+    /// Obtain the raw hardware encoder counter values, before overflow
+    /// correction and direction inversion.
+    ///
+    /// `QeiManager` does not give access to the underlying counter, so these
+    /// are the low 16 bits of the accumulated counts, which track the hardware
+    /// counters as long as sampling succeeds. They therefore reflect the
+    /// values seen at the last successful `read_and_update_positions()` call,
+    /// not a fresh read of the hardware.
+    ///
+    /// Useful for diagnosing encoder wiring faults: a raw value that never
+    /// changes points to a disconnected encoder.
+    pub fn raw_counts(&self) -> [u16; 2] {
+        let [left, right] = [self.encoders.0.count(), self.encoders.1.count()];
+        [left as u16, right as u16]
+    }
+}
+
+/// Enumeration across all the wheels of a four-wheel chassis.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Wheel4 {
+    /// The front left wheel.
+    FL = 0,
+    /// The front right wheel.
+    FR,
+    /// The rear left wheel.
+    RL,
+    /// The rear right wheel.
+    RR,
+}
+
+impl Wheel4 {
+    /// Obtain the index of the wheel.
+    ///
+    /// Wheels are indexed in the order `[FL, FR, RL, RR]`.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Models the TB6612FNG drive motors and encoders of a four-wheel chassis.
+///
+/// Arrays passed to and returned by this type are indexed as described by
+/// `Wheel4::index()`. All four motors are stopped (see `set_safe_state()`)
+/// when this is dropped. Use `into_parts()` to reclaim the underlying
+/// peripherals without doing so.
+///
+/// Only open-loop driving and raw position readings are supported. Unlike
+/// `Wheels`, this has no encoder inversion, position reset, velocity or
+/// odometry readings, and no duty cycle shaping (acceleration and duty
+/// limits, trim, deadband or supply voltage compensation). It also cannot
+/// be used with stall detection, the closed-loop drives or the controllers.
+pub struct Wheels4<T: Pwm, Q1: Qei, Q2: Qei, Q3: Qei, Q4: Qei, P: OutputPin> {
+    pwm: T,
+    ins: MotorPins<P, 4>,
+    channels: [T::Channel; 4],
+    encoders: (
+        QeiManager<Q1>,
+        QeiManager<Q2>,
+        QeiManager<Q3>,
+        QeiManager<Q4>,
+    ),
+    max_duty: Duty,
+    /// Last duty cycle commanded for each wheel.
+    duties: [Duty; 4],
+}
+
+impl<
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        Q3: Qei<Count = u16>,
+        Q4: Qei<Count = u16>,
+        P: OutputPin,
+    > Wheels4<T, Q1, Q2, Q3, Q4, P>
+{
+    /// Instantiates a new `Wheels4` representation.
+    ///
+    /// `ins` is an array of `[inA, inB]` pins. All arrays, as well as the
+    /// `encoders` tuple, must be ordered as `[FL, FR, RL, RR]`.
+    ///
+    /// The motors are left in the braked state after this function returns.
+    pub fn new(
+        mut pwm: T,
+        period: T::Time,
+        ins: [[P; 2]; 4],
+        channels: [T::Channel; 4],
+        encoders: (Q1, Q2, Q3, Q4),
+    ) -> Self {
+        for &channel in channels.iter() {
+            pwm.disable(channel);
+        }
+        pwm.set_period(period);
+        for &channel in channels.iter() {
+            pwm.enable(channel);
+        }
+
+        let max_duty = pwm.get_max_duty().into();
+
+        let mut out = Self {
+            pwm,
+            ins: MotorPins::new(ins),
+            channels,
+            encoders: (
+                QeiManager::new(encoders.0),
+                QeiManager::new(encoders.1),
+                QeiManager::new(encoders.2),
+                QeiManager::new(encoders.3),
+            ),
+            max_duty,
+            duties: [Duty::from_num(0); 4],
+        };
+
+        for which in [Wheel4::FL, Wheel4::FR, Wheel4::RL, Wheel4::RR] {
+            out.drive(which, Duty::from_num(0));
+        }
+        out
+    }
+
+    /// Sets the manner in which the motors are stopped when this is dropped.
+    ///
+    /// Defaults to `StopMode::Brake`.
+    pub fn set_safe_state(&mut self, mode: StopMode) {
+        self.ins.safe_state = mode;
+    }
+
+    /// Obtain the last duty cycle commanded for a motor.
+    pub fn duty(&self, which: Wheel4) -> Duty {
+        self.duties[which.index()]
+    }
+
+    /// Command a motor to coast.
+    pub fn coast(&mut self, which: Wheel4) {
+        self.duties[which.index()] = Duty::from_num(0);
+        self.ins[which.index()].coast()
+    }
+
+    /// Command a motor to stop in the given manner, with zero duty cycle.
+    pub fn stop(&mut self, which: Wheel4, mode: StopMode) {
+        self.duties[which.index()] = Duty::from_num(0);
+        self.pwm.set_duty(self.channels[which.index()], 0);
+        self.ins[which.index()].stop(mode);
+    }
+
+    /// Command a motor to be driven in a given direction at a provided
+    /// duty cycle.
+    ///
+    /// If `duty == 0`, the motor is actively braked. `duty` is clamped to
+    /// `[-1, 1]`.
+    pub fn drive(&mut self, which: Wheel4, duty: Duty) {
+        let duty = duty.clamp(Duty::from_num(-1), Duty::from_num(1));
+        self.duties[which.index()] = duty;
+
+        let control = &mut self.ins[which.index()];
+        if duty != 0 {
+            if duty > 0 {
+                control.cw();
+            } else {
+                control.ccw();
+            }
+        } else {
+            control.brake();
+        }
+
+        self.pwm.set_duty(
+            self.channels[which.index()],
+            (duty.abs() * self.max_duty).saturating_to_num(),
+        );
+    }
+
+    /// Reads the positions of all four motors' output shafts, while updating
+    /// the internal position counters to deal with hardware encoder counter
+    /// overflow.
+    ///
+    /// The position is given in terms of encoder counts.
+    ///
+    /// Must be called periodically to avoid sampling errors.
+    pub fn read_and_update_positions(&mut self) -> Result<[i64; 4], qei::SamplingError> {
+        self.encoders.0.sample()?;
+        self.encoders.1.sample()?;
+        self.encoders.2.sample()?;
+        self.encoders.3.sample()?;
+
+        Ok(self.read_positions())
+    }
+
+    /// Does the same as `read_and_update_positions`, except that the last
+    /// cached value is output instead.
+    pub fn read_positions(&self) -> [i64; 4] {
+        [
+            self.encoders.0.count(),
+            self.encoders.1.count(),
+            self.encoders.2.count(),
+            self.encoders.3.count(),
+        ]
+    }
+
+    /// Releases the underlying peripherals, in the same layout as accepted by
+    /// `new()`.
+    ///
+    /// The encoders are returned still wrapped in their `QeiManager`s. The
+    /// motors are left as-is, without being stopped.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(
+        self,
+    ) -> (
+        T,
+        [[P; 2]; 4],
+        [T::Channel; 4],
+        (
+            QeiManager<Q1>,
+            QeiManager<Q2>,
+            QeiManager<Q3>,
+            QeiManager<Q4>,
+        ),
+    ) {
+        (self.pwm, self.ins.release(), self.channels, self.encoders)
+    }
+}
+
+/// A single step of a `MotionSequence`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MotionPrimitive {
+    /// Drive the wheels at the given duty cycles (`[left, right]`) for a
+    /// given duration.
+    ///
+    /// The duty cycles are re-applied on every step, so they are ramped
+    /// towards if acceleration is limited. Opposing duty cycles turn the
+    /// vehicle in place.
+    Drive {
+        duties: [Duty; 2],
+        duration: Milliseconds<u32>,
+    },
+    /// Stop both wheels in the given manner, then wait for a given duration.
+    Stop {
+        mode: StopMode,
+        duration: Milliseconds<u32>,
+    },
+    /// Drive the steering servo to the given angle, then wait for it to
+    /// settle (see `Steering::is_settled()`).
+    Steer(Angle),
+}
+
+/// A sequence of up to `N` motion primitives, executed one after another.
+///
+/// The actuators are left in the state commanded by the last primitive once
+/// the sequence is done.
+pub struct MotionSequence<const N: usize> {
+    /// Primitives making up the sequence.
+    steps: Vec<MotionPrimitive, N>,
+    /// Index of the next primitive to start.
+    next: usize,
+    /// Index of the active primitive and the time at which it was started.
+    active: Option<(usize, Instant)>,
+}
+
+impl<const N: usize> MotionSequence<N> {
+    /// Creates a new, empty, sequence.
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            next: 0,
+            active: None,
+        }
+    }
+
+    /// Appends a primitive to the sequence.
+    ///
+    /// Returns the primitive back if the sequence is full.
+    pub fn push(&mut self, step: MotionPrimitive) -> Result<(), MotionPrimitive> {
+        self.steps.push(step)
+    }
+
+    /// Obtain the index of the active primitive, if any.
+    pub fn active(&self) -> Option<usize> {
+        self.active.map(|(index, _)| index)
+    }
+
+    /// Determines if every primitive in the sequence has completed.
+    pub fn is_done(&self) -> bool {
+        self.active.is_none() && self.next >= self.steps.len()
+    }
+
+    /// Advances the sequence, starting the next primitive once the active one
+    /// completes.
+    ///
+    /// Should be called periodically. Returns `true` once the sequence is
+    /// done.
+    pub fn step<T, Q1, Q2, P, S>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        steering: &mut Steering<S>,
+        now: Instant,
+    ) -> bool
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+        S: Pwm<Channel = Channel, Duty = u16, Time = Hertz>,
+    {
+        loop {
+            match self.active {
+                Some((index, since)) => {
+                    let elapsed = now
+                        .checked_duration_since(&since)
+                        .and_then(|elapsed| Milliseconds::<u32>::try_from(elapsed).ok())
+                        .unwrap_or(Milliseconds(0));
+                    let complete = match self.steps[index] {
+                        MotionPrimitive::Drive { duration, .. }
+                        | MotionPrimitive::Stop { duration, .. } => elapsed >= duration,
+                        MotionPrimitive::Steer(_) => steering.is_settled(now),
+                    };
+
+                    if !complete {
+                        if let MotionPrimitive::Drive { duties, .. } = self.steps[index] {
+                            // Keep ramping towards the duty cycles if
+                            // acceleration is limited.
+                            wheels.drive(Wheel::LEFT, duties[0]);
+                            wheels.drive(Wheel::RIGHT, duties[1]);
+                        }
+                        return false;
+                    }
+                    self.active = None;
+                }
+                None => {
+                    let index = self.next;
+                    let step = match self.steps.get(index) {
+                        Some(step) => *step,
+                        None => return true,
+                    };
+
+                    match step {
+                        MotionPrimitive::Drive { duties, .. } => {
+                            wheels.drive(Wheel::LEFT, duties[0]);
+                            wheels.drive(Wheel::RIGHT, duties[1]);
+                        }
+                        MotionPrimitive::Stop { mode, .. } => {
+                            wheels.stop(Wheel::LEFT, mode);
+                            wheels.stop(Wheel::RIGHT, mode);
+                        }
+                        MotionPrimitive::Steer(angle) => steering.set_at(angle, now),
+                    }
+
+                    self.next += 1;
+                    self.active = Some((index, now));
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for MotionSequence<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Measures the natural rolling deceleration of the vehicle while coasting.
+///
+/// `step()` should be called periodically (e.g. every few tens of
+/// milliseconds) with the vehicle in motion. The first call commands both
+/// motors to coast, and later calls sample the average wheel speed until the
+/// wheels stop turning. The result is then available from `deceleration()`.
+#[derive(Default)]
+pub struct CoastDown {
+    /// Positions at, and time of, the previous sample.
+    previous: Option<([i64; 2], Instant)>,
+    /// Speed measured at, and time of, the first speed sample, in encoder
+    /// counts per second.
+    initial: Option<(u64, Instant)>,
+    /// Whether the wheels have stopped turning.
+    done: bool,
+    /// Measured deceleration, if any.
+    deceleration: Option<Acceleration>,
+}
+
+impl CoastDown {
+    /// Creates a new deceleration measurement.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances the measurement.
+    ///
+    /// Returns `Ok(true)` once the wheels have stopped turning, after which
+    /// further calls do nothing.
+    pub fn step<T, Q1, Q2, P>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        now: Instant,
+    ) -> Result<bool, qei::SamplingError>
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+    {
+        if self.done {
+            return Ok(true);
+        }
+
+        let positions = wheels.read_and_update_positions()?;
+        let (previous, at) = match self.previous {
+            Some(previous) => previous,
+            None => {
+                wheels.coast(Wheel::LEFT);
+                wheels.coast(Wheel::RIGHT);
+                self.previous = Some((positions, now));
+                return Ok(false);
+            }
+        };
+
+        let elapsed = now
+            .checked_duration_since(&at)
+            .and_then(|elapsed| Milliseconds::<u32>::try_from(elapsed).ok())
+            .map_or(0, |elapsed| elapsed.0);
+        if elapsed == 0 {
+            return Ok(false);
+        }
+        self.previous = Some((positions, now));
+
+        let [left, right] = position_delta(previous, positions);
+        let speed = left
+            .unsigned_abs()
+            .saturating_add(right.unsigned_abs())
+            .saturating_mul(1000)
+            / (2 * u64::from(elapsed));
+
+        match self.initial {
+            None if speed == 0 => self.done = true,
+            None => self.initial = Some((speed, now)),
+            Some((initial, since)) if speed == 0 => {
+                let elapsed = now
+                    .checked_duration_since(&since)
+                    .and_then(|elapsed| Milliseconds::<u32>::try_from(elapsed).ok())
+                    .map_or(1, |elapsed| elapsed.0.max(1));
+                let counts = initial.saturating_mul(1000) / u64::from(elapsed);
+                let deceleration =
+                    wheels.counts_to_meters(i64::try_from(counts).unwrap_or(i64::MAX));
+                self.deceleration = Some(deceleration).filter(|d| *d > 0);
+                self.done = true;
+            }
+            Some(_) => {}
+        }
+
+        Ok(self.done)
+    }
+
+    /// Obtain the average deceleration since the first speed sample, in
+    /// meters per second squared, once `step()` has returned `Ok(true)`.
+    ///
+    /// Converted using the configured odometry (see
+    /// `Wheels::set_odometry()`). `None` until the wheels have stopped, as
+    /// well as if no deceleration was observed (the vehicle was already at
+    /// rest, or too slow to register) or odometry is not configured.
+    pub fn deceleration(&self) -> Option<Acceleration> {
+        self.deceleration
+    }
+}
+
+/// Gains of a PID controller.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PidGains {
+    /// Proportional gain, in units of duty per unit error.
+    pub kp: Gain,
+    /// Integral gain, in units of duty per unit error second.
+    pub ki: Gain,
+    /// Derivative gain, in units of duty per unit error per second.
+    pub kd: Gain,
+}
+
+/// State of a PID controller producing a duty cycle.
+#[derive(Copy, Clone, Debug, Default)]
+struct Pid {
+    /// Accumulated integral term, in units of duty.
+    ///
+    /// Kept within `[-1, 1]` to prevent windup.
+    integral: I32F32,
+    /// Error at the previous update, if any.
+    last_error: Option<I32F32>,
+}
+
+impl Pid {
+    /// Advances the controller by `dt` seconds, returning the duty cycle to
+    /// apply, within `[-1, 1]`.
+    fn update(&mut self, gains: &PidGains, error: I32F32, dt: I32F32) -> Duty {
+        let limit = I32F32::from_num(1);
+        self.integral = self
+            .integral
+            .saturating_add(I32F32::from_num(gains.ki).saturating_mul(error.saturating_mul(dt)))
+            .clamp(-limit, limit);
+
+        let derivative = match self.last_error.replace(error) {
+            Some(last) if dt > 0 => error
+                .saturating_sub(last)
+                .checked_div(dt)
+                .unwrap_or(I32F32::from_num(0)),
+            _ => I32F32::from_num(0),
+        };
+
+        let output = I32F32::from_num(gains.kp)
+            .saturating_mul(error)
+            .saturating_add(self.integral)
+            .saturating_add(I32F32::from_num(gains.kd).saturating_mul(derivative));
+        Duty::from_num(output.clamp(-limit, limit))
+    }
+}
+
+/// Closed-loop speed controller for the drive wheels.
+///
+/// Runs a PID loop per wheel on the speed measured through the encoders, in
+/// units of encoder counts per second. `update()` must be called
+/// periodically, and is the only thing that should drive the wheels while
+/// the controller is in use.
+pub struct WheelSpeedController {
+    /// Gains for each wheel, as `[left, right]`.
+    gains: [PidGains; 2],
+    /// Controller state for each wheel, as `[left, right]`.
+    pids: [Pid; 2],
+    /// Fastest wheel speed accepted by `drive_mps()`, in meters per second.
+    max_speed: Option<Speed>,
+}
+
+impl WheelSpeedController {
+    /// Creates a new controller using the same gains for both wheels.
+    pub fn new(gains: PidGains) -> Self {
+        Self {
+            gains: [gains; 2],
+            pids: [Pid::default(); 2],
+            max_speed: None,
+        }
+    }
+
+    /// Sets the fastest wheel speed, in meters per second, that
+    /// `drive_mps()` accepts, e.g. the speed the wheels reach at full duty.
+    ///
+    /// Unlimited by default. Panics if `max_speed` is not positive.
+    pub fn set_max_speed(&mut self, max_speed: Speed) {
+        assert!(max_speed > 0);
+        self.max_speed = Some(max_speed);
+    }
+
+    /// Sets the gains used for one wheel.
+    pub fn set_gains(&mut self, which: Wheel, gains: PidGains) {
+        self.gains[which.index()] = gains;
+    }
+
+    /// Discards the accumulated integral and derivative state, e.g. after
+    /// the wheels were driven by other means.
+    pub fn reset(&mut self) {
+        self.pids = [Pid::default(); 2];
+    }
+
+    /// Measures the wheel speeds and drives the wheels towards `targets`
+    /// (`[left, right]`, in encoder counts per second).
+    ///
+    /// `dt` is the time elapsed since the previous update. Returns the
+    /// measured speeds. The wheels are not driven if `dt` is zero.
+    pub fn update<T, Q1, Q2, P>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        targets: [i32; 2],
+        dt: Microseconds<u32>,
+    ) -> Result<[i32; 2], qei::SamplingError>
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+    {
+        let speeds = wheels.read_velocities(dt)?;
+        if dt.0 == 0 {
+            return Ok(speeds);
+        }
+
+        let seconds = I32F32::from_num(dt.0) / 1_000_000;
+        for which in [Wheel::LEFT, Wheel::RIGHT] {
+            let i = which.index();
+            let error = I32F32::from_num(targets[i]).saturating_sub(I32F32::from_num(speeds[i]));
+            let duty = self.pids[i].update(&self.gains[i], error, seconds);
+            wheels.drive(which, duty);
+        }
+
+        Ok(speeds)
+    }
+
+    /// Measures the wheel speeds and drives the chassis towards a forward
+    /// speed `v` (in meters per second) and yaw rate `omega` (in radians per
+    /// second).
+    ///
+    /// The wheel speeds are computed by `differential_kinematics()`, using
+    /// the odometry and track width configured on `wheels`. If odometry has
+    /// not been configured, the targets are zero and the wheels are brought
+    /// to a stop. Otherwise behaves as `update()`.
+    pub fn update_twist<T, Q1, Q2, P>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        v: Speed,
+        omega: AngularSpeed,
+        dt: Microseconds<u32>,
+    ) -> Result<[i32; 2], qei::SamplingError>
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+    {
+        let [left, right] = differential_kinematics(v, omega, wheels.track_width());
+        let targets = [wheels.speed_to_counts(left), wheels.speed_to_counts(right)];
+        self.update(wheels, targets, dt)
+    }
+
+    /// Measures the wheel speeds and drives the wheels towards `speeds`
+    /// (`[left, right]`, in meters per second).
+    ///
+    /// Speeds are converted into encoder counts per second using the
+    /// odometry configured on `wheels` (see `Wheels::set_odometry()`), which
+    /// must have been set. Otherwise behaves as `update()`, but returns the
+    /// targets applied, in encoder counts per second.
+    ///
+    /// The wheels are left untouched if a speed is faster than the maximum
+    /// set through `set_max_speed()`, or cannot be represented in encoder
+    /// counts per second.
+    pub fn drive_mps<T, Q1, Q2, P>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        speeds: [Speed; 2],
+        dt: Microseconds<u32>,
+    ) -> Result<[i32; 2], DriveError>
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+    {
+        if wheels.meters_per_count == 0 {
+            return Err(DriveError::NoOdometry);
+        }
+
+        let mut targets = [0; 2];
+        for (target, speed) in targets.iter_mut().zip(speeds) {
+            if self
+                .max_speed
+                .map_or(false, |max| speed.saturating_abs() > max)
+            {
+                return Err(DriveError::Unreachable);
+            }
+
+            *target = wheels.speed_to_counts(speed);
+            if *target == i32::MAX || *target == i32::MIN {
+                return Err(DriveError::Unreachable);
+            }
+        }
+
+        self.update(wheels, targets, dt)?;
+        Ok(targets)
+    }
+}
+
+/// Closed-loop position controller for the drive wheels.
+///
+/// Runs a PID loop per wheel on the position measured through the encoders,
+/// in units of encoder counts, driving each wheel to its target and holding
+/// it there. `update()` must be called periodically, and is the only thing
+/// that should drive the wheels while the controller is in use.
+pub struct WheelPositionController {
+    /// Gains for each wheel, as `[left, right]`.
+    gains: [PidGains; 2],
+    /// Controller state for each wheel, as `[left, right]`.
+    pids: [Pid; 2],
+    /// Largest position error, in encoder counts, at which a wheel is
+    /// considered to be at its target.
+    deadband: u32,
+}
+
+impl WheelPositionController {
+    /// Creates a new controller using the same gains for both wheels.
+    ///
+    /// Wheels within `deadband` encoder counts of their target are braked
+    /// instead of driven.
+    pub fn new(gains: PidGains, deadband: u32) -> Self {
+        Self {
+            gains: [gains; 2],
+            pids: [Pid::default(); 2],
+            deadband,
+        }
+    }
+
+    /// Sets the gains used for one wheel.
+    pub fn set_gains(&mut self, which: Wheel, gains: PidGains) {
+        self.gains[which.index()] = gains;
+    }
+
+    /// Sets the largest position error, in encoder counts, at which a wheel
+    /// is braked as being at its target.
+    pub fn set_deadband(&mut self, deadband: u32) {
+        self.deadband = deadband;
+    }
+
+    /// Discards the accumulated integral and derivative state, e.g. after
+    /// the wheels were driven by other means.
+    pub fn reset(&mut self) {
+        self.pids = [Pid::default(); 2];
+    }
+
+    /// Measures the wheel positions and drives the wheels towards `targets`
+    /// (`[left, right]`, in encoder counts, as returned by
+    /// `Wheels::read_positions()`).
+    ///
+    /// `dt` is the time elapsed since the previous update. Wheels within the
+    /// deadband of their target are braked, and their integral term cleared
+    /// so that it does not wind up while holding position. Returns the
+    /// remaining distance to the targets (see `remaining_to_target()`). The
+    /// wheels are not driven if `dt` is zero.
+    pub fn update<T, Q1, Q2, P>(
+        &mut self,
+        wheels: &mut Wheels<T, Q1, Q2, P>,
+        targets: [i64; 2],
+        dt: Microseconds<u32>,
+    ) -> Result<[i64; 2], qei::SamplingError>
+    where
+        T: Pwm<Duty = u16, Channel = Channel>,
+        Q1: Qei<Count = u16>,
+        Q2: Qei<Count = u16>,
+        P: OutputPin,
+    {
+        let remaining = remaining_to_target(wheels.read_and_update_positions()?, targets);
+        if dt.0 == 0 {
+            return Ok(remaining);
+        }
+
+        let seconds = I32F32::from_num(dt.0) / 1_000_000;
+        for which in [Wheel::LEFT, Wheel::RIGHT] {
+            let i = which.index();
+            if remaining[i].unsigned_abs() <= u64::from(self.deadband) {
+                self.pids[i] = Pid::default();
+                wheels.stop(which, StopMode::Brake);
+            } else {
+                let error = I32F32::saturating_from_num(remaining[i]);
+                let duty = self.pids[i].update(&self.gains[i], error, seconds);
+                wheels.drive(which, duty);
+            }
+        }
+
+        Ok(remaining)
+    }
+}
+
+/// Computes the speed of each wheel of a differential drive chassis needed
+/// to move forward at `v` (in meters per second) while turning at `omega`
+/// (in radians per second, positive counter-clockwise).
+///
+/// `track_width` is the distance between the contact points of the left and
+/// right wheels, in meters. Returns `[left, right]` wheel speeds in meters per
+/// second, saturating if they cannot be represented.
+pub fn differential_kinematics(
+    v: Speed,
+    omega: AngularSpeed,
+    track_width: TravelDistance,
+) -> [Speed; 2] {
+    let turn = omega.saturating_mul(track_width) / 2;
+    [v.saturating_sub(turn), v.saturating_add(turn)]
+}
+
+/// Computes the signed change in position of each wheel between two
+/// snapshots returned by `Wheels::read_positions()`.
+///
+/// `prev` must have been read before `cur`, from the same `Wheels` instance.
+/// The subtraction wraps instead of overflowing, so this never panics, but
+/// the result is meaningless if the position counters were re-initialized
+/// (e.g. by `Wheels::reset_positions()`) between the two snapshots.
+pub fn position_delta(prev: [i64; 2], cur: [i64; 2]) -> [i64; 2] {
+    [cur[0].wrapping_sub(prev[0]), cur[1].wrapping_sub(prev[1])]
+}
+
+/// Computes the rate of change of an encoder count, in counts per second.
+///
+/// `delta` is the change in count over `dt`. Evaluated in 128 bits so that it
+/// cannot overflow; zero if `dt` is zero.
+fn counts_per_second(delta: i64, dt: Microseconds<u32>) -> i128 {
+    if dt.0 == 0 {
+        0
+    } else {
+        i128::from(delta) * 1_000_000 / i128::from(dt.0)
+    }
+}
+
+/// Computes the signed distance remaining for each wheel to reach a target
+/// position.
+///
+/// Positions are given in terms of encoder counts, as returned by
+/// `Wheels::read_positions()`. A negative remaining distance means that the
+/// wheel has already overshot the target.
+pub fn remaining_to_target(current: [i64; 2], target: [i64; 2]) -> [i64; 2] {
+    [
+        target[0].wrapping_sub(current[0]),
+        target[1].wrapping_sub(current[1]),
+    ]
+}
+
+/// Computes the instantaneous turn radius implied by the two wheel speeds.
+///
+/// `track_width` is the distance between the two wheels. The radius is
+/// measured from the center of the vehicle, in the same units as
+/// `track_width`. Wheel speeds may be given in any (common) unit.
+///
+/// A positive radius corresponds to a turn towards the left (the right wheel
+/// moving faster), and zero to rotation in place.
+///
+/// Returns `None` if the wheel speeds are equal (the vehicle is moving in a
+/// straight line, with an infinite turn radius), or if the radius is too
+/// large to be represented.
+pub fn turn_radius(left: Q17_15, right: Q17_15, track_width: Q17_15) -> Option<Q17_15> {
+    let difference = right.checked_sub(left)?;
+    if difference == 0 {
+        return None;
+    }
+
+    (track_width / 2)
+        .checked_mul(right.checked_add(left)?)?
+        .checked_div(difference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::mock::{MockPwm, MockQei, Pin};
+
+    type MockWheels = Wheels<MockPwm, MockQei, MockQei, Pin>;
+
+    /// Creates a servo driver on channel 1 of a 50000 count PWM generator.
+    fn steering() -> (Steering<MockPwm>, MockPwm) {
+        let pwm = MockPwm::new(50_000);
+        (Steering::new(pwm.clone(), Channel::C1), pwm)
+    }
+
+    /// Creates wheels driven by channels 1 (left) and 2 (right) of a 1000
+    /// count PWM generator, along with the `[left, right]` encoders.
+    fn wheels() -> (MockWheels, MockPwm, [MockQei; 2]) {
+        let pwm = MockPwm::new(1_000);
+        let encoders = [MockQei::default(), MockQei::default()];
+        let wheels = Wheels::new(
+            pwm.clone(),
+            Hertz(20_000),
+            Default::default(),
+            [Channel::C1, Channel::C2],
+            (encoders[0].clone(), encoders[1].clone()),
+        );
+        (wheels, pwm, encoders)
+    }
+
+    /// Configures odometry of exactly 1/1024 meters per encoder count.
+    fn set_odometry(wheels: &mut MockWheels) {
+        wheels.meters_per_count = I32F32::from_num(1) / 1024;
+    }
+
+    #[test]
+    fn remaining_to_target_undershoot() {
+        assert_eq!(remaining_to_target([100, -50], [250, -200]), [150, -150]);
+        assert_eq!(remaining_to_target([250, -200], [250, -200]), [0, 0]);
+
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        encoders[0].advance(100);
+        encoders[1].advance(-50);
+        wheels.read_and_update_positions().unwrap();
+        assert_eq!(
+            wheels.remaining_distance([612, -562]),
+            [q17_15!(0.5), q17_15!(-0.5)]
+        );
+    }
+
+    #[test]
+    fn remaining_to_target_overshoot() {
+        assert_eq!(remaining_to_target([300, -260], [250, -200]), [-50, 60]);
+        assert_eq!(remaining_to_target([0, i64::MIN], [0, i64::MAX]), [0, -1]);
+
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        encoders[0].advance(300);
+        encoders[1].advance(-260);
+        wheels.read_and_update_positions().unwrap();
+        assert_eq!(
+            wheels.remaining_distance([44, -4]),
+            [q17_15!(-0.25), q17_15!(0.25)]
+        );
+    }
+
+    #[test]
+    fn position_delta_forward_and_backward() {
+        assert_eq!(position_delta([100, -20], [250, 30]), [150, 50]);
+        assert_eq!(position_delta([250, 30], [100, -20]), [-150, -50]);
+        assert_eq!(position_delta([7, 7], [7, 7]), [0, 0]);
+    }
+
+    #[test]
+    fn position_delta_wraps() {
+        assert_eq!(
+            position_delta([i64::MAX, i64::MIN], [i64::MIN, i64::MAX]),
+            [1, -1]
+        );
+        assert_eq!(position_delta([i64::MAX - 1, 0], [i64::MIN + 1, 0]), [3, 0]);
+    }
+
+    #[test]
+    fn turn_radius_straight() {
+        let speed = q17_15!(0.5);
+        assert_eq!(turn_radius(speed, speed, q17_15!(0.25)), None);
+        assert_eq!(turn_radius(-speed, -speed, q17_15!(0.25)), None);
+    }
+
+    #[test]
+    fn turn_radius_gentle_curve() {
+        let (slow, fast) = (q17_15!(0.375), q17_15!(0.625));
+        assert_eq!(turn_radius(slow, fast, q17_15!(0.25)), Some(q17_15!(0.5)));
+        assert_eq!(turn_radius(fast, slow, q17_15!(0.25)), Some(q17_15!(-0.5)));
+    }
+
+    #[test]
+    fn turn_radius_spin_in_place() {
+        let speed = q17_15!(0.5);
+        assert_eq!(turn_radius(-speed, speed, q17_15!(0.25)), Some(q17_15!(0)));
+        assert_eq!(turn_radius(speed, -speed, q17_15!(0.25)), Some(q17_15!(0)));
+    }
+
+    #[test]
+    fn steering_degrees_at_extremes_and_center() {
+        let (mut steering, pwm) = steering();
+
+        steering.set_degrees(q17_15!(0));
+        assert_eq!(steering.angle(), q17_15!(0));
+        assert_eq!(steering.degrees(), q17_15!(0));
+        let neutral = pwm.duty(Channel::C1);
+
+        steering.set_degrees(q17_15!(25));
+        assert_eq!(steering.angle(), q17_15!(1));
+        assert_eq!(steering.degrees(), q17_15!(25));
+        let max = pwm.duty(Channel::C1);
+
+        steering.set_degrees(q17_15!(-25));
+        assert_eq!(steering.angle(), q17_15!(-1));
+        assert_eq!(steering.degrees(), q17_15!(-25));
+        let min = pwm.duty(Channel::C1);
+
+        assert!(min < neutral && neutral < max);
+    }
+
+    #[test]
+    fn steering_degrees_clamp_to_full_scale() {
+        let (mut steering, _) = steering();
+        steering.set_full_scale_degrees(q17_15!(30));
+
+        steering.set_degrees(q17_15!(15));
+        assert_eq!(steering.angle(), q17_15!(0.5));
+        assert_eq!(steering.degrees(), q17_15!(15));
+
+        steering.set_degrees(q17_15!(45));
+        assert_eq!(steering.degrees(), q17_15!(30));
+        steering.set_degrees(q17_15!(-45));
+        assert_eq!(steering.degrees(), q17_15!(-30));
+    }
+
+    #[test]
+    fn sweep_reverses_at_both_endpoints() {
+        let mut sweep = SteeringSweep::new(q17_15!(-0.5), q17_15!(0.5), q17_15!(0.25));
+        for angle in [-0.25, 0.0, 0.25, 0.5, 0.25, 0.0, -0.25, -0.5, -0.25, 0.0] {
+            assert_eq!(sweep.step(), Angle::from_num(angle));
+        }
+    }
+
+    #[test]
+    fn sweep_stops_at_limits() {
+        // Limits are swapped and clamped to [-1, 1], and the rate made
+        // positive. Steps overshooting a limit stop at it.
+        let mut sweep = SteeringSweep::new(q17_15!(2), q17_15!(-2), q17_15!(-0.75));
+        for angle in [-0.25, 0.5, 1.0, 0.25, -0.5, -1.0, -0.25] {
+            assert_eq!(sweep.step(), Angle::from_num(angle));
+        }
+    }
+
+    #[test]
+    fn steering_saturates_out_of_range_angles() {
+        let (mut steering, pwm) = steering();
+        steering.set(q17_15!(1));
+        let max = pwm.duty(Channel::C1);
+        steering.set(q17_15!(-1));
+        let min = pwm.duty(Channel::C1);
+        assert!(0 < min && min < max && max < pwm.get_max_duty());
+
+        assert_eq!(steering.set(q17_15!(2)), q17_15!(1));
+        assert_eq!(pwm.duty(Channel::C1), max);
+        assert_eq!(steering.set(q17_15!(-2)), q17_15!(-1));
+        assert_eq!(pwm.duty(Channel::C1), min);
+
+        // Trim cannot push the servo past its limits either.
+        steering.set_trim(q17_15!(0.5));
+        assert_eq!(steering.set(q17_15!(2)), q17_15!(1));
+        assert_eq!(pwm.duty(Channel::C1), max);
+    }
+
+    #[test]
+    fn steering_unsettled_until_modeled_time_passes() {
+        let (mut steering, _) = steering();
+        let at = |ms: u32| Instant::new(ms);
+        assert!(steering.is_settled(at(0)));
+
+        // Full-range step: the whole travel time.
+        steering.set(q17_15!(-1));
+        steering.set_at(q17_15!(1), at(1_000));
+        assert!(!steering.is_settled(at(1_000)));
+        assert!(!steering.is_settled(at(1_479)));
+        assert!(steering.is_settled(at(1_480)));
+
+        // Quarter of the range.
+        steering.set_at(q17_15!(0.5), at(2_000));
+        assert!(!steering.is_settled(at(2_119)));
+        assert!(steering.is_settled(at(2_120)));
+    }
+
+    #[test]
+    fn coast_down_measures_deceleration() {
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        wheels.drive(Wheel::LEFT, q17_15!(0.5));
+        wheels.drive(Wheel::RIGHT, q17_15!(0.5));
+
+        let mut coast = CoastDown::new();
+        assert!(!coast.step(&mut wheels, Instant::new(0)).unwrap());
+        assert_eq!(wheels.duty(Wheel::LEFT), 0);
+        assert_eq!(wheels.duty(Wheel::RIGHT), 0);
+
+        // 1000 counts/s, decaying linearly to rest over 400 ms.
+        for (i, counts) in [100, 75, 50, 25].iter().enumerate() {
+            encoders[0].advance(*counts);
+            encoders[1].advance(*counts);
+            let now = Instant::new(100 * (i as u32 + 1));
+            assert!(!coast.step(&mut wheels, now).unwrap());
+            assert_eq!(coast.deceleration(), None);
+        }
+        assert!(coast.step(&mut wheels, Instant::new(500)).unwrap());
+
+        // 2500 counts/s^2.
+        assert_eq!(coast.deceleration(), Some(q17_15!(2.44140625)));
+        assert!(coast.step(&mut wheels, Instant::new(600)).unwrap());
+    }
+
+    #[test]
+    fn coast_down_without_motion() {
+        let (mut wheels, _, _) = wheels();
+        set_odometry(&mut wheels);
+
+        let mut coast = CoastDown::new();
+        assert!(!coast.step(&mut wheels, Instant::new(0)).unwrap());
+        assert!(coast.step(&mut wheels, Instant::new(100)).unwrap());
+        assert_eq!(coast.deceleration(), None);
+    }
+
+    #[test]
+    fn sequence_runs_two_steps() {
+        let (mut wheels, pwm, _) = wheels();
+        let (mut steering, _) = steering();
+        let mut sequence = MotionSequence::<2>::new();
+        let half = q17_15!(0.5);
+        sequence
+            .push(MotionPrimitive::Drive {
+                duties: [half, -half],
+                duration: Milliseconds(100),
+            })
+            .unwrap();
+        sequence
+            .push(MotionPrimitive::Stop {
+                mode: StopMode::Brake,
+                duration: Milliseconds(50),
+            })
+            .unwrap();
+        assert!(!sequence.is_done());
+        assert_eq!(sequence.active(), None);
+
+        let mut step = |ms: u32| sequence.step(&mut wheels, &mut steering, Instant::new(ms));
+        assert!(!step(0));
+        assert!(!step(99));
+        assert_eq!(pwm.duty(Channel::C1), 500);
+        assert_eq!(pwm.duty(Channel::C2), 500);
+
+        assert!(!step(100));
+        assert_eq!(pwm.duty(Channel::C1), 0);
+        assert_eq!(pwm.duty(Channel::C2), 0);
+        assert!(!step(149));
+        assert!(step(150));
+        assert!(step(200));
+
+        assert!(sequence.is_done());
+        assert_eq!(sequence.active(), None);
+        assert_eq!(wheels.duty(Wheel::LEFT), 0);
+    }
+
+    #[test]
+    fn sequence_reports_active_step() {
+        let (mut wheels, _, _) = wheels();
+        let (mut steering, _) = steering();
+        let mut sequence = MotionSequence::<2>::new();
+        let stop = MotionPrimitive::Stop {
+            mode: StopMode::Coast,
+            duration: Milliseconds(10),
+        };
+        sequence.push(stop).unwrap();
+        sequence.push(stop).unwrap();
+        assert_eq!(sequence.push(stop), Err(stop));
+
+        sequence.step(&mut wheels, &mut steering, Instant::new(0));
+        assert_eq!(sequence.active(), Some(0));
+        sequence.step(&mut wheels, &mut steering, Instant::new(10));
+        assert_eq!(sequence.active(), Some(1));
+    }
+
+    #[test]
+    fn drive_clamps_out_of_range_duty() {
+        let (mut wheels, pwm, _) = wheels();
+
+        wheels.drive(Wheel::LEFT, q17_15!(1.5));
+        assert_eq!(wheels.duty(Wheel::LEFT), q17_15!(1));
+        assert_eq!(pwm.duty(Channel::C1), 1_000);
+
+        wheels.drive(Wheel::RIGHT, q17_15!(-1.5));
+        assert_eq!(wheels.duty(Wheel::RIGHT), q17_15!(-1));
+        assert_eq!(pwm.duty(Channel::C2), 1_000);
+
+        wheels.set_duty_limit(q17_15!(0.5));
+        wheels.drive(Wheel::LEFT, q17_15!(1.5));
+        assert_eq!(wheels.duty(Wheel::LEFT), q17_15!(0.5));
+        assert_eq!(pwm.duty(Channel::C1), 500);
+    }
+
+    #[test]
+    fn inverted_wheel_reports_flipped_sign() {
+        let (mut wheels, _, encoders) = wheels();
+        wheels.set_invert(Wheel::RIGHT, true);
+
+        encoders[0].advance(100);
+        encoders[1].advance(100);
+        assert_eq!(wheels.read_and_update_positions().unwrap(), [100, -100]);
+        assert_eq!(wheels.raw_counts(), [100, 100]);
+
+        // Positions are reinterpreted as if the encoder had never been
+        // flipped.
+        wheels.set_invert(Wheel::RIGHT, false);
+        assert_eq!(wheels.read_positions(), [100, 100]);
+        encoders[1].advance(10);
+        assert_eq!(wheels.read_and_update_positions().unwrap(), [100, 110]);
+    }
+
+    #[test]
+    fn total_distance_accumulates_back_and_forth() {
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        let mut travel = |left: i16, right: i16| {
+            encoders[0].advance(left);
+            encoders[1].advance(right);
+            wheels.read_and_update_positions().unwrap();
+        };
+
+        // 1 m forward, then back, in 0.25 m samples.
+        for _ in 0..4 {
+            travel(256, 256);
+        }
+        for _ in 0..4 {
+            travel(-256, -256);
+        }
+        // Turning in place does not count.
+        travel(512, -512);
+        travel(-512, 512);
+
+        assert_eq!(wheels.distance_traveled(), [q17_15!(0); 2]);
+        assert_eq!(wheels.total_distance(), q17_15!(2));
+
+        // Nor does moving the origin.
+        wheels.reset_positions();
+        travel(-512, -512);
+        assert_eq!(wheels.total_distance(), q17_15!(2.5));
+    }
+
+    #[test]
+    fn straight_drive_survives_reset() {
+        let (mut wheels, _, encoders) = wheels();
+        encoders[0].advance(300);
+        wheels.read_and_update_positions().unwrap();
+
+        let half = q17_15!(0.5);
+        wheels.drive_straight(half);
+        encoders[0].advance(10);
+        encoders[1].advance(10);
+        wheels.read_and_update_positions().unwrap();
+        wheels.reset_positions();
+
+        encoders[0].advance(10);
+        encoders[1].advance(10);
+        wheels.update_straight().unwrap();
+        assert_eq!(wheels.duty(Wheel::LEFT), half);
+        assert_eq!(wheels.duty(Wheel::RIGHT), half);
+    }
+
+    #[test]
+    fn straight_drive_survives_inversion() {
+        let (mut wheels, _, encoders) = wheels();
+        wheels.set_straight_gain(i8f24!(0.001953125));
+        encoders[0].advance(300);
+        encoders[1].advance(300);
+        wheels.read_and_update_positions().unwrap();
+
+        wheels.drive_straight(q17_15!(0.5));
+        encoders[0].advance(10);
+        encoders[1].advance(10);
+        wheels.read_and_update_positions().unwrap();
+
+        // The right wheel now reads as having moved back 10 counts since the
+        // start of the drive, rather than 610.
+        wheels.set_invert(Wheel::RIGHT, true);
+        wheels.update_straight().unwrap();
+        assert_eq!(wheels.duty(Wheel::LEFT), q17_15!(0.4609375));
+        assert_eq!(wheels.duty(Wheel::RIGHT), q17_15!(0.5390625));
+    }
+
+    #[test]
+    fn distance_drive_survives_reset() {
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        wheels.drive_distance(q17_15!(0.5), q17_15!(0.5));
+
+        encoders[0].advance(256);
+        encoders[1].advance(256);
+        assert!(!wheels.update_distance().unwrap());
+        wheels.reset_positions();
+
+        // Odd deltas averaging to exactly the remaining 256 counts.
+        encoders[0].advance(255);
+        encoders[1].advance(257);
+        assert!(wheels.update_distance().unwrap());
+        assert_eq!(wheels.duty(Wheel::LEFT), 0);
+    }
+
+    #[test]
+    fn distance_drive_survives_inversion() {
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        wheels.drive_distance(q17_15!(0.25), q17_15!(0.5));
+
+        encoders[0].advance(100);
+        encoders[1].advance(100);
+        assert!(!wheels.update_distance().unwrap());
+
+        // The right wheel now reads as having moved back 100 counts.
+        wheels.set_invert(Wheel::RIGHT, true);
+        assert!(!wheels.update_distance().unwrap());
+        encoders[0].advance(256);
+        encoders[1].advance(-256);
+        assert!(wheels.update_distance().unwrap());
+    }
+
+    #[test]
+    fn distance_drive_makes_up_pushback() {
+        let (mut wheels, _, encoders) = wheels();
+        set_odometry(&mut wheels);
+        wheels.drive_distance(q17_15!(0.25), q17_15!(0.5));
+
+        let mut travel = |counts: i16| {
+            encoders[0].advance(counts);
+            encoders[1].advance(counts);
+            wheels.update_distance().unwrap()
+        };
+        assert!(!travel(-100));
+        assert!(!travel(300));
+        assert!(travel(56));
+    }
+
+    #[test]
+    fn wheels4_indexes_all_four_wheels() {
+        let pwm = MockPwm::new(1_000);
+        let ins: [[Pin; 2]; 4] = Default::default();
+        let encoders: [MockQei; 4] = Default::default();
+        let mut wheels = Wheels4::new(
+            pwm.clone(),
+            Hertz(20_000),
+            ins.clone(),
+            [Channel::C1, Channel::C2, Channel::C3, Channel::C4],
+            (
+                encoders[0].clone(),
+                encoders[1].clone(),
+                encoders[2].clone(),
+                encoders[3].clone(),
+            ),
+        );
+
+        let duties = [q17_15!(0.25), q17_15!(-0.5), q17_15!(0.75), q17_15!(1)];
+        let wheel4s = [Wheel4::FL, Wheel4::FR, Wheel4::RL, Wheel4::RR];
+        for (&which, &duty) in wheel4s.iter().zip(duties.iter()) {
+            wheels.drive(which, duty);
+        }
+        for (i, &which) in wheel4s.iter().enumerate() {
+            assert_eq!(which.index(), i);
+            assert_eq!(wheels.duty(which), duties[i]);
+        }
+        assert_eq!(pwm.duty(Channel::C1), 250);
+        assert_eq!(pwm.duty(Channel::C2), 500);
+        assert_eq!(pwm.duty(Channel::C3), 750);
+        assert_eq!(pwm.duty(Channel::C4), 1_000);
+        assert!(ins[0][0].is_high() && !ins[0][1].is_high());
+        assert!(!ins[1][0].is_high() && ins[1][1].is_high());
+
+        for (i, encoder) in encoders.iter().enumerate() {
+            encoder.advance(i as i16 + 1);
+        }
+        encoders[1].advance(-4);
+        assert_eq!(wheels.read_and_update_positions().unwrap(), [1, -2, 3, 4]);
+        assert_eq!(wheels.read_positions(), [1, -2, 3, 4]);
     }
 }
@@ -1,11 +1,84 @@
-use core::convert::{TryFrom, TryInto};
-use embedded_hal::digital::v2::StatefulOutputPin;
+use super::lrtimer::LrTimer;
+use core::convert::{Infallible, TryFrom, TryInto};
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
 use embedded_time::{
     duration::{Generic, Microseconds},
     Clock, Instant,
 };
-use fixed::types::U16F16 as DistanceImpl;
-use fixed_macro::types::U16F16 as distance;
+use fixed::types::{I16F16, I32F32, U0F32, U16F16 as DistanceImpl, U32F32};
+use fixed_macro::types::{I16F16 as temperature, I32F32 as variance, U16F16 as distance};
+use heapless::Deque;
+
+#[cfg(feature = "sim")]
+pub mod sim;
+
+#[cfg(feature = "async")]
+pub mod asynch;
+
+/// Helpers (de)serializing `embedded_time` types that do not implement
+/// `serde` traits.
+#[cfg(feature = "serde")]
+mod serde_support {
+    /// (De)serializes an `Instant` as its raw tick count.
+    pub mod instant {
+        use embedded_time::{Clock, Instant};
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<C: Clock<T = u32>, S: Serializer>(
+            instant: &Instant<C>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.serialize_u32(instant.duration_since_epoch().integer())
+        }
+
+        pub fn deserialize<'de, C: Clock<T = u32>, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Instant<C>, D::Error> {
+            u32::deserialize(deserializer).map(Instant::new)
+        }
+    }
+
+    /// (De)serializes an optional `Instant` as its raw tick count.
+    pub mod optional_instant {
+        use embedded_time::{Clock, Instant};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<C: Clock<T = u32>, S: Serializer>(
+            instant: &Option<Instant<C>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            instant
+                .map(|instant| instant.duration_since_epoch().integer())
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, C: Clock<T = u32>, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Instant<C>>, D::Error> {
+            Option::<u32>::deserialize(deserializer).map(|ticks| ticks.map(Instant::new))
+        }
+    }
+
+    /// (De)serializes an optional duration in microseconds.
+    pub mod width {
+        use embedded_time::duration::Microseconds;
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub fn serialize<S: Serializer>(
+            width: &Option<Microseconds<u32>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            width.map(|width| width.0).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Microseconds<u32>>, D::Error> {
+            Option::<u32>::deserialize(deserializer).map(|width| width.map(Microseconds))
+        }
+    }
+}
+
 /// Driver for the HC-SR04 ultrasonic sensor.
 
 /// Distance type used for measurements.
@@ -17,28 +90,186 @@ pub type Distance = DistanceImpl;
 /// measurement to have timed out.
 ///
 /// The HC-SR04 datasheet suggests a timeout of 60 milliseconds.
+///
+/// Used as the default timeout, and the upper bound for timeouts configured
+/// through `Sr04::with_timeout()`.
 pub const TIMEOUT: Microseconds = Microseconds(60_000);
 
 /// Minimum width of the trigger pulse.
 pub const TRIGGER_WIDTH: Microseconds = Microseconds(10);
 
+/// Time after the start of the measurement beyond which the trigger pin is
+/// driven low even if `Event::TriggerComplete` has not been received.
+///
+/// Ideally this would be a small multiple of `TRIGGER_WIDTH`, but it is
+/// checked against the low-resolution clock, which may only advance once
+/// every millisecond. Anything shorter would fire spuriously whenever a
+/// measurement starts just before a clock tick.
+pub const TRIGGER_TIMEOUT: Microseconds = Microseconds(2_000);
+
 /// Convers the width of the ECHO pulse (in units of microseconds)
 /// to meters.
 ///
 /// Based on the speed of sound at 25 deg C (346.06 m/s).
+///
+/// Used as the default scaling factor. Note that as a `Distance`, this is
+/// quantized quite coarsely: factors computed by `Sr04::set_temperature()`
+/// are more precise.
 pub const SCALING_FACTOR: Distance = distance!(0.00017303);
 
-/// Minimum width of the echo pulse.
+/// Scaling factor type, converting echo pulse widths (in units of
+/// microseconds) to meters.
+pub type ScalingFactor = U0F32;
+
+/// Signed distance type, used for calibration offsets.
+///
+/// In units of meters.
+pub type DistanceOffset = I16F16;
+
+/// Velocity type.
+///
+/// In units of meters per second.
+pub type Velocity = I16F16;
+
+/// Variance type, used for tuning the Kalman filter.
+pub type Variance = I32F32;
+
+/// Temperature type.
 ///
-/// Any pulses with widths smaller than this are considered to be glitches.
+/// In units of degrees Celsius.
+pub type Temperature = I16F16;
+
+/// Lowest temperature supported by `Sr04::set_temperature()`.
+pub const MIN_TEMPERATURE: Temperature = temperature!(-40);
+
+/// Highest temperature supported by `Sr04::set_temperature()`.
+pub const MAX_TEMPERATURE: Temperature = temperature!(85);
+
+// `EchoWidth` relies on this to convert widths to `u16` without truncation.
+const _: () = assert!(TIMEOUT.0 <= u16::MAX as u32);
+
+/// Width of an echo pulse, clamped to at most `TIMEOUT`.
+///
+/// Since `TIMEOUT` fits in a `u16`, so does every `EchoWidth`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EchoWidth(u16);
+
+impl EchoWidth {
+    /// Creates a new echo width, clamping `width` to `TIMEOUT`.
+    pub fn new(width: Microseconds<u32>) -> Self {
+        // Cannot truncate: guaranteed by the assertion on `TIMEOUT` above.
+        Self(core::cmp::min(width, TIMEOUT).0 as u16)
+    }
+
+    /// Obtain the width in microseconds, as a `u16`.
+    pub fn as_u16(self) -> u16 {
+        self.0
+    }
+
+    /// Obtain the width as a duration.
+    pub fn duration(self) -> Microseconds<u32> {
+        Microseconds(self.0 as u32)
+    }
+}
+
+/// Obtain the smallest distinguishable change in measured distance.
+///
+/// This is the distance corresponding to one microsecond of echo pulse width,
+/// i.e. `SCALING_FACTOR`. See `Sr04::distance_resolution()` for that of a
+/// configured sensor.
+pub fn distance_resolution() -> Distance {
+    SCALING_FACTOR
+}
+
+/// Convert a distance in meters to centimeters.
+///
+/// Saturates at `Distance::MAX`, which is far beyond the sensor's range.
+pub fn to_centimeters(d: Distance) -> Distance {
+    d.saturating_mul(distance!(100))
+}
+
+/// Convert a distance in meters to inches.
+///
+/// Saturates at `Distance::MAX`, which is far beyond the sensor's range.
+pub fn to_inches(d: Distance) -> Distance {
+    d.saturating_mul(distance!(39.370079))
+}
+
+/// Obtain the scaling factor accounting for the speed of sound at the given
+/// ambient temperature.
+///
+/// See `Sr04::set_temperature()`.
+fn scaling_factor_at(celsius: Temperature) -> ScalingFactor {
+    let celsius = celsius.clamp(MIN_TEMPERATURE, MAX_TEMPERATURE);
+    let speed = temperature!(331.3) + temperature!(0.606) * celsius;
+    // Halved, since the pulse covers the distance twice, and converted to
+    // meters per microsecond.
+    ScalingFactor::from_num(U32F32::from_num(speed) / 2_000_000)
+}
+
+/// Default minimum width of the echo pulse.
+///
+/// Any pulses with widths smaller than this are considered to be glitches,
+/// and reported as `Error::TooShort`.
 pub const MINIMUM_ECHO_WIDTH: Microseconds = Microseconds(200);
 
+/// Default maximum width of the echo pulse.
+///
+/// Corresponds to roughly 4 meters, the rated range of the HC-SR04. Any
+/// pulses with widths larger than this are reported as `Error::TooLong`.
+pub const MAXIMUM_ECHO_WIDTH: Microseconds = Microseconds(23_200);
+
+/// Spread of measured distances at which `Sr04::confidence()` is halved.
+pub const CONFIDENCE_SPREAD: Distance = distance!(0.05);
+
+/// Default process noise of the Kalman filter: the variance of the
+/// acceleration of measured objects, in (m/s^2)^2.
+pub const DEFAULT_PROCESS_NOISE: Variance = variance!(1);
+
+/// Default measurement noise of the Kalman filter: the variance of measured
+/// distances, in m^2.
+pub const DEFAULT_MEASUREMENT_NOISE: Variance = variance!(0.0001);
+
+/// Variance of the velocity estimate when the Kalman filter is (re)started,
+/// in (m/s)^2.
+const INITIAL_VELOCITY_VARIANCE: Variance = variance!(1);
+
+/// Readings further apart than this restart the Kalman filter.
+const KALMAN_MAX_GAP: Microseconds = Microseconds(1_000_000);
+
+/// Default window within which an echo edge following the rising edge is
+/// considered to be a duplicate delivery of the rising edge.
+///
+/// Far shorter than any echo pulse that could be accepted.
+pub const DUPLICATE_ECHO_WINDOW: Microseconds = Microseconds(5);
+
+/// Default time allowed for a sensor powered up by `Sr04::trigger()` to
+/// receive its settle ticks, before the measurement fails with
+/// `Error::PowerTimeout`.
+pub const SETTLE_TIMEOUT: Microseconds = Microseconds(100_000);
+
+/// State of the constant-velocity Kalman filter estimating distance.
+struct Kalman<LRCLOCK: Clock> {
+    /// Start time of the last reading.
+    at: Instant<LRCLOCK>,
+    /// Estimated distance, in meters.
+    distance: I32F32,
+    /// Estimated rate of change of distance, in meters per second.
+    velocity: I32F32,
+    /// Covariance of the estimate, as `[distance, covariance, velocity]`.
+    p: [I32F32; 3],
+}
+
 enum MeasurementState<HRCLOCK: Clock> {
     /// Trigger pin has been pulled high.
     AfterTriggerRising,
     /// Trigger pin has been pulled low after it has been high for
     /// `TRIGGER_WIDTH`.
-    AfterTriggerFalling,
+    AfterTriggerFalling {
+        /// Whether the trigger pin was pulled low at `TRIGGER_TIMEOUT`,
+        /// without `Event::TriggerComplete` having been received yet.
+        forced: bool,
+    },
     /// After a rising edge has been detected on the echo pin.
     AfterEchoRising {
         /// Time at which the rising edge was detected on the pin.
@@ -50,6 +281,13 @@ enum MeasurementState<HRCLOCK: Clock> {
 enum State<HRCLOCK: Clock, LRCLOCK: Clock> {
     /// Sensor is idle - no measurement has been started.
     Idle,
+    /// Sensor has been powered up, but has not yet settled.
+    PoweringUp {
+        /// Time at which the sensor was powered up.
+        since: Instant<LRCLOCK>,
+        /// Number of `Event::PowerTick`s left before the measurement starts.
+        remaining: u8,
+    },
     /// A measurement has been started.
     Measuring {
         /// Measurement start time.
@@ -59,27 +297,311 @@ enum State<HRCLOCK: Clock, LRCLOCK: Clock> {
     },
 }
 
+/// Phase of the driver, as reported by `Sr04::phase()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MeasurementPhase {
+    /// No measurement is in progress.
+    Idle,
+    /// Sensor is powered up, awaiting `Event::PowerTick`s before the trigger
+    /// pin is driven high.
+    PoweringUp,
+    /// Trigger pin is high, awaiting `Event::TriggerComplete`.
+    TriggerHigh,
+    /// Trigger pulse is complete, awaiting the rising edge on the echo pin.
+    AwaitingEcho,
+    /// Echo pin is high, awaiting its falling edge.
+    EchoHigh,
+}
+
+/// Outcome of checking for timeouts, as returned by `Sr04::tick()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Progress {
+    /// No measurement was completed.
+    Pending,
+    /// A measurement was completed and recorded.
+    Completed,
+    /// The measurement failed transiently and was restarted, with the trigger
+    /// pin driven high (see `Sr04::set_max_retries()`).
+    Retrying,
+}
+
 /// Measurement information.
-pub struct Measurement<LRCLOCK: Clock> {
+///
+/// With the `serde` feature, measurements taken with a `u32` clock can be
+/// (de)serialized. Instants are represented by their raw tick counts, and
+/// echo pulse widths in microseconds.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound = "LRCLOCK: Clock<T = u32>, HRCLOCK: Clock<T = u32>")
+)]
+pub struct Measurement<LRCLOCK: Clock, HRCLOCK: Clock = LRCLOCK> {
     /// Measurement start time.
+    #[cfg_attr(feature = "serde", serde(with = "serde_support::instant"))]
     pub start: Instant<LRCLOCK>,
     /// Measurement end time.
+    #[cfg_attr(feature = "serde", serde(with = "serde_support::instant"))]
     pub end: Instant<LRCLOCK>,
     /// Measurement result.
     pub result: Result<Distance, Error>,
+    /// Width of the echo pulse, before conversion to a distance.
+    ///
+    /// `None` if no complete echo pulse was received.
+    #[cfg_attr(feature = "serde", serde(with = "serde_support::width"))]
+    pub echo_width: Option<Microseconds<u32>>,
+    /// Time at which the rising edge of the echo pulse was detected.
+    ///
+    /// `None` if no falling edge was received.
+    #[cfg_attr(feature = "serde", serde(with = "serde_support::optional_instant"))]
+    pub echo_rise: Option<Instant<HRCLOCK>>,
+    /// Time at which the falling edge of the echo pulse was detected.
+    ///
+    /// `None` if no falling edge was received.
+    #[cfg_attr(feature = "serde", serde(with = "serde_support::optional_instant"))]
+    pub echo_fall: Option<Instant<HRCLOCK>>,
+}
+
+impl<LRCLOCK: Clock, HRCLOCK: Clock> Measurement<LRCLOCK, HRCLOCK> {
+    /// Creates a new measurement.
+    ///
+    /// Meant for constructing measurements without going through the driver,
+    /// e.g. when testing code consuming them.
+    ///
+    /// The echo pulse width and edge times are left unset.
+    ///
+    /// Returns `None` if `end` is earlier than `start`.
+    ///
+    /// ```
+    /// use controller_core::board::{
+    ///     lrtimer::LrTimer,
+    ///     sr04::{Distance, Error, Measurement},
+    /// };
+    /// use embedded_time::Instant;
+    ///
+    /// let start = Instant::<LrTimer>::new(100);
+    /// let end = Instant::<LrTimer>::new(112);
+    ///
+    /// let m = Measurement::<LrTimer>::new(start, end, Ok(Distance::from_num(0.5))).unwrap();
+    /// assert_eq!(m.result.unwrap(), Distance::from_num(0.5));
+    /// assert!(m.echo_width.is_none());
+    ///
+    /// assert!(Measurement::<LrTimer>::new(end, start, Err(Error::NoEcho)).is_none());
+    /// ```
+    pub fn new(
+        start: Instant<LRCLOCK>,
+        end: Instant<LRCLOCK>,
+        result: Result<Distance, Error>,
+    ) -> Option<Self> {
+        if end < start {
+            None
+        } else {
+            Some(Self {
+                start,
+                end,
+                result,
+                echo_width: None,
+                echo_rise: None,
+                echo_fall: None,
+            })
+        }
+    }
+
+    /// Obtain the rate of change of distance from `previous` to this
+    /// measurement.
+    ///
+    /// Negative when approaching an object. Measurements are timestamped at
+    /// their `start`, so the resolution is limited by that of `LRCLOCK`.
+    ///
+    /// Returns `None` if either measurement failed, if this did not start
+    /// after `previous`, or if the velocity cannot be represented.
+    pub fn velocity_since(&self, previous: &Self) -> Option<Velocity>
+    where
+        Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+    {
+        let (previous_distance, current_distance) = match (previous.result, self.result) {
+            (Ok(previous), Ok(current)) => (previous, current),
+            _ => return None,
+        };
+
+        let dt = self.start.checked_duration_since(&previous.start)?;
+        let dt = Microseconds::<u32>::try_from(dt).ok()?;
+        if dt.0 == 0 {
+            return None;
+        }
+
+        let change = I32F32::from_num(current_distance) - I32F32::from_num(previous_distance);
+        let seconds = I32F32::from_num(dt.0) / 1_000_000;
+        Velocity::checked_from_num(change.checked_div(seconds)?)
+    }
+}
+
+impl<LRCLOCK: Clock, HRCLOCK: Clock> Clone for Measurement<LRCLOCK, HRCLOCK> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<LRCLOCK: Clock, HRCLOCK: Clock> Copy for Measurement<LRCLOCK, HRCLOCK> {}
+
+/// Size of a measurement record, in bytes.
+pub const RECORD_SIZE: usize = 15;
+
+impl<HRCLOCK: Clock> Measurement<LrTimer, HRCLOCK> {
+    /// Serializes the measurement into a fixed-size record.
+    ///
+    /// The record is laid out as follows, with all fields little-endian:
+    ///
+    /// - `[0, 4)`: start time, in milliseconds.
+    /// - `[4, 8)`: end time, in milliseconds.
+    /// - `[8]`: result code: `0` for a distance, otherwise an error code.
+    /// - `[9, 13)`: raw bits of the distance, or zero for an error.
+    /// - `[13, 15)`: echo pulse width in microseconds, or `0xffff` if unset.
+    ///   Widths are clamped to `TIMEOUT`.
+    ///
+    /// Echo edge times are not recorded.
+    pub fn to_record(&self) -> [u8; RECORD_SIZE] {
+        let (code, distance) = match self.result {
+            Ok(distance) => (0, distance.to_bits()),
+            Err(error) => (error.code(), 0),
+        };
+
+        let mut record = [0; RECORD_SIZE];
+        record[0..4].copy_from_slice(&self.start.duration_since_epoch().integer().to_le_bytes());
+        record[4..8].copy_from_slice(&self.end.duration_since_epoch().integer().to_le_bytes());
+        record[8] = code;
+        record[9..13].copy_from_slice(&distance.to_le_bytes());
+        record[13..15].copy_from_slice(
+            &self
+                .echo_width
+                .map_or(u16::MAX, |width| EchoWidth::new(width).as_u16())
+                .to_le_bytes(),
+        );
+        record
+    }
+
+    /// Deserializes a measurement from a record produced by `to_record()`.
+    ///
+    /// Returns `None` if the record contains an unknown result code.
+    pub fn from_record(record: &[u8; RECORD_SIZE]) -> Option<Self> {
+        let word = |at: usize| {
+            u32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+        };
+
+        Some(Self {
+            start: Instant::new(word(0)),
+            end: Instant::new(word(4)),
+            result: match record[8] {
+                0 => Ok(Distance::from_bits(word(9))),
+                code => Err(Error::from_code(code)?),
+            },
+            echo_width: match u16::from_le_bytes([record[13], record[14]]) {
+                u16::MAX => None,
+                width => Some(Microseconds(width as u32)),
+            },
+            echo_rise: None,
+            echo_fall: None,
+        })
+    }
+}
+
+/// Measurement information, together with metadata useful for judging its
+/// quality.
+///
+/// Built from the last cached `Measurement` by `Sr04::last_detailed()`.
+#[derive(Debug, Copy, Clone)]
+pub struct DetailedMeasurement {
+    /// Measurement result.
+    pub result: Result<Distance, Error>,
+    /// Width of the echo pulse, if a complete pulse was received.
+    pub echo_width: Option<Microseconds<u32>>,
+    /// Time elapsed between the start and end of the measurement, as
+    /// measured by the low-resolution clock.
+    ///
+    /// `None` if the elapsed time could not be represented.
+    pub elapsed: Option<Microseconds<u32>>,
+    /// Confidence in recent measurements, as returned by `Sr04::confidence()`.
+    pub confidence: u8,
+    /// Phase the driver was in when the measurement completed, e.g.
+    /// `MeasurementPhase::AwaitingEcho` if it timed out without an echo.
+    pub phase: MeasurementPhase,
 }
 
 /// Errors that can be returned from the sensor.
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Error {
     /// A measurement is already in progress.
     InProgress,
-    /// Measurement timed out.
+    /// Measurement timed out before the trigger pulse was completed.
     Timeout,
+    /// Measurement timed out without a rising edge on the echo pin.
+    ///
+    /// Usually means that no object is in range.
+    NoEcho,
+    /// Measurement timed out after a rising edge on the echo pin, without a
+    /// falling edge.
+    ///
+    /// Usually means that the echo pin is stuck high.
+    EchoStuckHigh,
+    /// `Event::TriggerComplete` was not received within `TRIGGER_TIMEOUT`,
+    /// nor before the measurement timed out without an echo.
+    ///
+    /// The trigger pin is driven low at `TRIGGER_TIMEOUT` regardless, and an
+    /// echo received afterwards still completes the measurement.
+    TriggerTimeout,
     /// Sensor measured a distance that was abnormally short.
     TooShort,
+    /// Sensor measured a distance beyond the maximum echo width.
+    ///
+    /// Usually means that no object is within range.
+    TooLong,
     /// An unexpected event was provided.
     Unexpected,
+    /// The sensor did not receive its settle ticks within the settle timeout
+    /// after being powered up.
+    ///
+    /// See `Sr04::set_settle_timeout()`.
+    PowerTimeout,
+}
+
+impl Error {
+    /// Determines if the error is likely transient, and the measurement
+    /// worth retrying.
+    fn is_transient(self) -> bool {
+        matches!(self, Error::Timeout | Error::NoEcho | Error::TooShort)
+    }
+
+    /// Obtain the (non-zero) code identifying the error in measurement
+    /// records.
+    fn code(self) -> u8 {
+        match self {
+            Error::InProgress => 1,
+            Error::Timeout => 2,
+            Error::TriggerTimeout => 3,
+            Error::NoEcho => 4,
+            Error::EchoStuckHigh => 5,
+            Error::TooShort => 6,
+            Error::Unexpected => 7,
+            Error::TooLong => 8,
+            Error::PowerTimeout => 9,
+        }
+    }
+
+    /// Obtain the error identified by a code returned from `code()`.
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Error::InProgress),
+            2 => Some(Error::Timeout),
+            3 => Some(Error::TriggerTimeout),
+            4 => Some(Error::NoEcho),
+            5 => Some(Error::EchoStuckHigh),
+            6 => Some(Error::TooShort),
+            7 => Some(Error::Unexpected),
+            8 => Some(Error::TooLong),
+            9 => Some(Error::PowerTimeout),
+            _ => None,
+        }
+    }
 }
 
 /// Events that can be passed to the driver.
@@ -89,6 +611,28 @@ pub enum Event<HRCLOCK: Clock> {
     TriggerComplete,
     /// An rising / falling edge interrupt occurred on the echo pin.
     EchoInterrupt(Instant<HRCLOCK>),
+    /// A settling period tick elapsed while the sensor is powering up.
+    ///
+    /// See `Sr04::with_enable()`.
+    PowerTick,
+}
+
+/// Placeholder for the enable pin of an `Sr04` that is always powered.
+///
+/// Driving it does nothing.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NoEnable;
+
+impl OutputPin for NoEnable {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 /// Driver structure.
@@ -97,43 +641,286 @@ pub enum Event<HRCLOCK: Clock> {
 /// `HRCLOCK`: High-resolution (microsecond-level) clock.
 /// `LRCLOCK`: Low-resolution clock used to record the start and end timestamps,
 ///            as well as detect timeouts.
-pub struct Sr04<TRIG, HRCLOCK: Clock, LRCLOCK: Clock> {
+/// `N`: Number of completed measurements retained for `drain_measurements()`.
+/// `W`: Number of successful measurements used for filtering.
+/// `K`: Number of completed measurements retained for `history()`, and used
+///      by `confidence()`. Zero disables the history.
+/// `EN`: Pin enabling power to the sensor, or `NoEnable`.
+pub struct Sr04<
+    TRIG,
+    HRCLOCK: Clock,
+    LRCLOCK: Clock,
+    const N: usize = 8,
+    const W: usize = 5,
+    const K: usize = 8,
+    EN = NoEnable,
+> {
     /// Trigger pin.
     trig: TRIG,
+    /// Enable pin, driven high while measuring.
+    en: EN,
+    /// Number of `Event::PowerTick`s to wait for after enabling the sensor.
+    settle_ticks: u8,
+    /// Time allowed for the settle ticks to be received.
+    settle_timeout: Microseconds,
     /// State of the driver.
     state: State<HRCLOCK, LRCLOCK>,
     /// Last measurement recorded.
-    last: Option<Measurement<LRCLOCK>>,
+    last: Option<Measurement<LRCLOCK, HRCLOCK>>,
+    /// Phase in which the last measurement recorded completed.
+    last_phase: MeasurementPhase,
+    /// Number of measurements recorded, wrapping around.
+    completions: u32,
+    /// Last `K` measurements recorded, oldest first.
+    history: Deque<Measurement<LRCLOCK, HRCLOCK>, K>,
+    /// Number of consecutive failed measurements.
+    consecutive_errors: u32,
+    /// Completed measurements not yet drained.
+    queue: Deque<Measurement<LRCLOCK, HRCLOCK>, N>,
+    /// Window within which repeated echo edges are ignored.
+    duplicate_echo_window: Microseconds,
+    /// Factor converting echo pulse widths to distances.
+    scaling_factor: ScalingFactor,
+    /// Time after the start of a measurement beyond which it times out.
+    timeout: Microseconds,
+    /// Echo pulses narrower than this are reported as `Error::TooShort`.
+    min_echo_width: Microseconds,
+    /// Echo pulses wider than this are reported as `Error::TooLong`.
+    max_echo_width: Microseconds,
+    /// Gain applied to measured distances.
+    gain: Distance,
+    /// Offset applied to measured distances, after `gain`.
+    offset: DistanceOffset,
+    /// Maximum number of times a measurement is retried.
+    max_retries: u8,
+    /// Number of times the measurement in progress has been retried.
+    retries: u8,
+    /// Last `W` successfully measured distances.
+    window: Deque<Distance, W>,
+    /// Kalman filter state, if any distance has been measured.
+    kalman: Option<Kalman<LRCLOCK>>,
+    /// Process noise of the Kalman filter.
+    process_noise: Variance,
+    /// Measurement noise of the Kalman filter.
+    measurement_noise: Variance,
 }
 
-impl<TRIG: StatefulOutputPin, HRCLOCK: Clock, LRCLOCK: Clock> Sr04<TRIG, HRCLOCK, LRCLOCK>
+impl<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        LRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+    > Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K>
 where
     Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
     Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
 {
-    /// Create a new `Sr04` instance.
+    /// Create a new `Sr04` instance for an always-powered sensor.
     pub fn new(trig: TRIG) -> Self {
+        Self::with_enable(trig, NoEnable, 0)
+    }
+}
+
+impl<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        LRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+        EN: OutputPin,
+    > Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K, EN>
+where
+    Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+    Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+{
+    /// Create a new `Sr04` instance for a sensor powered through `en`.
+    ///
+    /// The sensor is powered up by `trigger()`, and powered down once the
+    /// measurement completes. After powering up, the caller must pass
+    /// `settle_ticks` `Event::PowerTick`s to `process()` before the trigger
+    /// pin is driven high. If they are not all received within the settle
+    /// timeout (see `set_settle_timeout()`), the measurement fails with
+    /// `Error::PowerTimeout`.
+    pub fn with_enable(trig: TRIG, mut en: EN, settle_ticks: u8) -> Self {
+        en.set_low().ok();
         Self {
             trig,
+            en,
+            settle_ticks,
+            settle_timeout: SETTLE_TIMEOUT,
             state: State::Idle,
             last: None,
+            last_phase: MeasurementPhase::Idle,
+            completions: 0,
+            history: Deque::new(),
+            consecutive_errors: 0,
+            queue: Deque::new(),
+            duplicate_echo_window: DUPLICATE_ECHO_WINDOW,
+            scaling_factor: ScalingFactor::from_num(SCALING_FACTOR),
+            timeout: TIMEOUT,
+            min_echo_width: MINIMUM_ECHO_WIDTH,
+            max_echo_width: MAXIMUM_ECHO_WIDTH,
+            gain: distance!(1),
+            offset: DistanceOffset::from_num(0),
+            max_retries: 0,
+            retries: 0,
+            window: Deque::new(),
+            kalman: None,
+            process_noise: DEFAULT_PROCESS_NOISE,
+            measurement_noise: DEFAULT_MEASUREMENT_NOISE,
         }
     }
 
+    /// Sets the time after the start of a measurement beyond which it is
+    /// considered to have timed out.
+    ///
+    /// Echo pulses are also clamped to this width. A shorter timeout allows
+    /// for a higher sample rate when objects are known to be close.
+    ///
+    /// Defaults to `TIMEOUT`, which is also the longest timeout allowed.
+    pub fn with_timeout(mut self, timeout: Microseconds) -> Self {
+        self.timeout = core::cmp::min(timeout, TIMEOUT);
+        self
+    }
+
+    /// Sets the time allowed for the settle ticks to be received after the
+    /// sensor is powered up (see `with_enable()`).
+    ///
+    /// Like other timeouts, this is only detected on the next call that takes
+    /// the current time. Defaults to `SETTLE_TIMEOUT`.
+    pub fn set_settle_timeout(&mut self, timeout: Microseconds) {
+        self.settle_timeout = timeout;
+    }
+
+    /// Sets the factor used to convert echo pulse widths (in units of
+    /// microseconds) to meters.
+    ///
+    /// Defaults to `SCALING_FACTOR`.
+    pub fn set_scaling_factor(&mut self, factor: ScalingFactor) {
+        self.scaling_factor = factor;
+    }
+
+    /// Sets the scaling factor to account for the speed of sound at the given
+    /// ambient temperature.
+    ///
+    /// The speed of sound is modelled as `331.3 + 0.606 * T` m/s. `celsius`
+    /// is clamped to `[MIN_TEMPERATURE, MAX_TEMPERATURE]`.
+    pub fn set_temperature(&mut self, celsius: Temperature) {
+        self.scaling_factor = scaling_factor_at(celsius);
+    }
+
+    /// Sets a linear calibration, `gain * raw + offset`, applied to measured
+    /// distances.
+    ///
+    /// The offset is signed so that sensors reading long can be corrected.
+    /// Calibrated distances saturate at zero and `Distance::MAX`. Echo pulse
+    /// widths are left uncalibrated.
+    ///
+    /// Defaults to a gain of one and an offset of zero.
+    pub fn set_calibration(&mut self, gain: Distance, offset: DistanceOffset) {
+        self.gain = gain;
+        self.offset = offset;
+    }
+
+    /// Converts an echo pulse width to a calibrated distance.
+    ///
+    /// Returns `None` if the distance cannot be represented.
+    fn distance(&self, width: EchoWidth) -> Option<Distance> {
+        let raw = Distance::checked_from_num(
+            U32F32::from_num(width.as_u16()).checked_mul(U32F32::from_num(self.scaling_factor))?,
+        )?;
+        Some(Distance::saturating_from_num(
+            I32F32::from_num(raw.saturating_mul(self.gain)) + I32F32::from_num(self.offset),
+        ))
+    }
+
+    /// Obtain the smallest distinguishable change in measured distance.
+    ///
+    /// Unlike the free function `distance_resolution()`, this accounts for
+    /// the scaling factor (see `set_temperature()`) and the calibration gain
+    /// (see `set_calibration()`).
+    pub fn distance_resolution(&self) -> Distance {
+        Distance::saturating_from_num(
+            U32F32::from_num(self.scaling_factor).saturating_mul(U32F32::from_num(self.gain)),
+        )
+    }
+
+    /// Sets the width below which echo pulses are reported as
+    /// `Error::TooShort`.
+    ///
+    /// Defaults to `MINIMUM_ECHO_WIDTH`. Lowering it allows close objects to
+    /// be measured, at the cost of accepting more glitches.
+    pub fn set_min_echo_width(&mut self, width: Microseconds) {
+        self.min_echo_width = width;
+    }
+
+    /// Sets the width beyond which echo pulses are reported as
+    /// `Error::TooLong`.
+    ///
+    /// Defaults to `MAXIMUM_ECHO_WIDTH`. Widths are clamped to the measurement
+    /// timeout before being compared, so pulses at or above the timeout are
+    /// also reported as `Error::TooLong` unless the maximum is at or above the
+    /// timeout.
+    pub fn set_max_echo_width(&mut self, width: Microseconds) {
+        self.max_echo_width = width;
+    }
+
+    /// Sets the number of times a measurement failing with a transient error
+    /// (`Timeout`, `NoEcho` or `TooShort`) is retried before the failure is
+    /// recorded.
+    ///
+    /// A retry restarts the measurement with the trigger pin driven high,
+    /// exactly like `trigger()`: `process()` then returns `Ok(false)` instead
+    /// of `Ok(true)`, `tick()` returns `Progress::Retrying`, and `phase()`
+    /// returns `MeasurementPhase::TriggerHigh`. The caller must pass
+    /// `Event::TriggerComplete` to `process()` after a duration of
+    /// `TRIGGER_WIDTH`, as usual.
+    ///
+    /// Retries are only started by `process()` and `tick()`. Timeouts detected
+    /// by any other call (e.g. `trigger()` or `measurement()`) are recorded
+    /// without being retried, so that the trigger pin is never driven high
+    /// behind the caller's back.
+    ///
+    /// Defaults to zero, i.e. no retries.
+    pub fn set_max_retries(&mut self, n: u8) {
+        self.max_retries = n;
+    }
+
+    /// Sets the window within which an echo edge following the rising edge
+    /// is considered a duplicate delivery of the rising edge, and ignored.
+    ///
+    /// Defaults to `DUPLICATE_ECHO_WINDOW`.
+    pub fn set_duplicate_echo_window(&mut self, window: Microseconds) {
+        self.duplicate_echo_window = window;
+    }
+
     /// Trigger the sensor.
     ///
     /// An `Ok()` result requires that the caller pass `Event::TriggerComplete`
     /// to `process()` after a duration of `TRIGGER_WIDTH`.
+    ///
+    /// If the sensor has settle ticks configured (see `with_enable()`), the
+    /// sensor is powered up instead, and the trigger pin is only driven high
+    /// once they have elapsed. `phase()` then returns
+    /// `MeasurementPhase::TriggerHigh`.
     pub fn trigger(&mut self, at: Instant<LRCLOCK>) -> Result<(), Error> {
-        self.poll(at);
+        self.poll(at, false);
         match self.state {
             State::Idle => {
-                self.trig.set_high().ok();
+                self.en.set_high().ok();
+                self.retries = 0;
 
-                self.state = State::Measuring {
-                    start: at,
-                    state: MeasurementState::AfterTriggerRising,
-                };
+                if self.settle_ticks == 0 {
+                    self.begin(at);
+                } else {
+                    self.state = State::PoweringUp {
+                        since: at,
+                        remaining: self.settle_ticks,
+                    };
+                }
 
                 Ok(())
             }
@@ -141,103 +928,1427 @@ where
         }
     }
 
+    /// Drives the trigger pin high, starting a measurement at `at`.
+    fn begin(&mut self, at: Instant<LRCLOCK>) {
+        self.trig.set_high().ok();
+        self.state = State::Measuring {
+            start: at,
+            state: MeasurementState::AfterTriggerRising,
+        };
+    }
+
+    /// Abandons the measurement in progress, if any.
+    ///
+    /// The trigger pin is driven low, the sensor is powered down and no
+    /// `Measurement` is recorded, so `trigger()` can be called again
+    /// immediately. Events for the abandoned measurement that arrive later
+    /// are rejected with `Error::Unexpected`.
+    pub fn abort(&mut self) {
+        self.trig.set_low().ok();
+        self.en.set_low().ok();
+        self.state = State::Idle;
+        self.retries = 0;
+    }
+
     /// Obtain the last complete measurement, if any.
-    pub fn measurement(&mut self, at: Instant<LRCLOCK>) -> Option<&Measurement<LRCLOCK>> {
-        self.poll(at);
+    pub fn measurement(&mut self, at: Instant<LRCLOCK>) -> Option<&Measurement<LRCLOCK, HRCLOCK>> {
+        self.poll(at, false);
 
         self.last.as_ref()
     }
 
-    /// Returns the currently set state of the trigger pin.
-    pub fn is_trig_high(&self) -> bool {
-        self.trig.is_set_high().unwrap_or(false)
+    /// Obtain the last (up to) `K` complete measurements, most recent first.
+    ///
+    /// Unlike `drain_measurements()`, this does not remove them. Always empty
+    /// unless `K` is non-zero.
+    pub fn history(&self) -> impl Iterator<Item = &Measurement<LRCLOCK, HRCLOCK>> {
+        self.history.iter().rev()
     }
 
-    /// Handles time-based driver state machine transitions.
+    /// Obtain the last complete measurement, if any, along with its
+    /// associated metadata.
     ///
-    /// Returns `true` if this resulted in a measurement being completed.
-    fn poll(&mut self, at: Instant<LRCLOCK>) -> bool {
-        match self.state {
-            State::Measuring { start, .. } => {
-                let elapsed: Microseconds<u32> = (at - start).try_into().unwrap_or(TIMEOUT);
-                if elapsed >= TIMEOUT {
-                    self.state = State::Idle;
-                    self.last = Some(Measurement {
-                        start,
-                        end: at,
-                        /// Disables the ECHO interrupt & clears associated interrupt bits.
-                        result: Err(Error::Timeout),
-                    });
-                    true
-                } else {
-                    false
-                }
-            }
-            _ => false,
+    /// Unlike `measurement()`, this does not advance the driver state machine.
+    pub fn last_detailed(&self) -> Option<DetailedMeasurement> {
+        self.last.as_ref().map(|m| DetailedMeasurement {
+            result: m.result,
+            echo_width: m.echo_width,
+            elapsed: m
+                .end
+                .checked_duration_since(&m.start)
+                .and_then(|elapsed| elapsed.try_into().ok()),
+            confidence: self.confidence(),
+            phase: self.last_phase,
+        })
+    }
+
+    /// Determines if the sensor is healthy, i.e. whether fewer than
+    /// `max_consecutive` measurements have failed in a row.
+    ///
+    /// A single successful measurement restores health.
+    pub fn is_healthy(&self, max_consecutive: u32) -> bool {
+        self.consecutive_errors < max_consecutive
+    }
+
+    /// Returns the number of measurements that have failed in a row.
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors
+    }
+
+    /// Removes and returns all completed measurements not yet drained, oldest
+    /// first.
+    ///
+    /// Up to `N` measurements are retained between calls; when more complete
+    /// in the meantime, the oldest ones are dropped. This is independent of
+    /// the last measurement returned by `measurement()`.
+    pub fn drain_measurements(
+        &mut self,
+    ) -> impl Iterator<Item = Measurement<LRCLOCK, HRCLOCK>> + '_ {
+        core::iter::from_fn(move || self.queue.pop_front())
+    }
+
+    /// Obtain the mean of the last (up to) `W` successfully measured
+    /// distances.
+    ///
+    /// Failed measurements are not included. Returns `None` if there are no
+    /// successful measurements since the filter was last reset.
+    pub fn filtered_measurement(&self) -> Option<Distance> {
+        if self.window.is_empty() {
+            return None;
         }
+
+        let sum = self
+            .window
+            .iter()
+            .fold(Distance::from_num(0), |sum, d| sum.saturating_add(*d));
+        Some(sum / self.window.len() as u32)
     }
 
-    /// Process an event.
+    /// Obtain the median of the last (up to) `W` successfully measured
+    /// distances.
     ///
-    /// Returns `Ok(true)` if the event resulted in a measurement being
-    /// completed.
-    pub fn process(&mut self, event: Event<HRCLOCK>, at: Instant<LRCLOCK>) -> Result<bool, Error> {
-        if self.poll(at) {
-            return Ok(true);
+    /// Unlike the mean returned by `filtered_measurement()`, this is robust to
+    /// isolated outliers. With an even number of distances, the mean of the
+    /// two middle distances is returned.
+    ///
+    /// Returns `None` if there are no successful measurements since the
+    /// filter was last reset.
+    pub fn median_distance(&self) -> Option<Distance> {
+        let len = self.window.len();
+        if len == 0 {
+            return None;
         }
 
-        match self.state {
-            State::Idle => Err(Error::Unexpected),
-            State::Measuring {
-                start,
-                ref mut state,
-            } => {
-                match state {
-                    MeasurementState::AfterTriggerRising => {
-                        if let Event::TriggerComplete = event {
-                            self.trig.set_low().ok();
-                            *state = MeasurementState::AfterTriggerFalling;
-                        } else {
-                            return Err(Error::Unexpected);
-                        }
-                    }
-                    MeasurementState::AfterTriggerFalling => {
-                        if let Event::EchoInterrupt(rise) = event {
-                            *state = MeasurementState::AfterEchoRising { rise };
-                        } else {
-                            return Err(Error::Unexpected);
-                        }
-                    }
-                    MeasurementState::AfterEchoRising { rise } => {
-                        if let Event::EchoInterrupt(fall) = event {
-                            // Clamp width to timeout.
-                            // Because it should be impossible for the width to exceed 60_000us
-                            // unless the two timers are derived from the same clock / have
-                            // significantly different precision.
-                            let echo_duration: Microseconds<u32> = core::cmp::min(
-                                (fall - *rise).try_into().unwrap_or(TIMEOUT),
-                                TIMEOUT,
-                            );
-                            self.last = Some(Measurement {
-                                start,
-                                end: at,
-                                result: if echo_duration < MINIMUM_ECHO_WIDTH {
-                                    Err(Error::TooShort)
-                                } else {
-                                    // echo_duration.0 guaranteed to be smaller than max(u16) because
-                                    // of clamp.
-                                    Ok(Distance::from_num(echo_duration.0 as u16) * SCALING_FACTOR)
-                                },
-                            });
-                            self.state = State::Idle;
-                            return Ok(true);
-                        } else {
-                            return Err(Error::Unexpected);
-                        }
-                    }
-                }
-                Ok(false)
-            }
+        let mut sorted = [Distance::from_num(0); W];
+        for (slot, d) in sorted.iter_mut().zip(self.window.iter()) {
+            *slot = *d;
         }
+        let sorted = &mut sorted[..len];
+        sorted.sort_unstable();
+
+        let upper = sorted[len / 2];
+        Some(if len % 2 == 1 {
+            upper
+        } else {
+            let lower = sorted[len / 2 - 1];
+            lower + (upper - lower) / 2
+        })
+    }
+
+    /// Obtain a score from 0 (untrustworthy) to 255 (consistent) for recent
+    /// measurements, computed over the measurements in `history()`:
+    ///
+    /// 1. The spread `s` is the mean absolute deviation of the successfully
+    ///    measured distances from their mean.
+    /// 2. The consistency is `255 * CONFIDENCE_SPREAD / (CONFIDENCE_SPREAD + s)`,
+    ///    i.e. 255 for identical distances, halving at a spread of
+    ///    `CONFIDENCE_SPREAD`.
+    /// 3. The consistency is scaled by the fraction of measurements that were
+    ///    successful, penalizing recent errors.
+    ///
+    /// Returns 0 if there are no successful measurements in the history, and
+    /// hence always if `K` is zero.
+    pub fn confidence(&self) -> u8 {
+        let distances = || self.history.iter().filter_map(|m| m.result.ok());
+        let successes = distances().count() as u32;
+        if successes == 0 {
+            return 0;
+        }
+
+        let sum = distances().fold(U32F32::from_num(0), |sum, d| sum + U32F32::from_num(d));
+        let mean = sum / successes;
+        let deviation = distances().fold(U32F32::from_num(0), |sum, d| {
+            let d = U32F32::from_num(d);
+            sum + if d > mean { d - mean } else { mean - d }
+        });
+        let spread = deviation / successes;
+
+        let threshold = U32F32::from_num(CONFIDENCE_SPREAD);
+        let consistency = U32F32::from_num(255) * threshold / (threshold + spread);
+        let score = consistency * successes / self.history.len() as u32;
+        score.to_num::<u32>().min(255) as u8
+    }
+
+    /// Sets the process noise of the Kalman filter: the variance of the
+    /// acceleration of measured objects, in (m/s^2)^2.
+    ///
+    /// Larger values track changes faster, but smooth less. Negative values
+    /// are treated as zero. Defaults to `DEFAULT_PROCESS_NOISE`.
+    pub fn set_process_noise(&mut self, q: Variance) {
+        self.process_noise = q.max(Variance::from_num(0));
+    }
+
+    /// Sets the measurement noise of the Kalman filter: the variance of
+    /// measured distances, in m^2.
+    ///
+    /// Negative values are treated as zero. Defaults to
+    /// `DEFAULT_MEASUREMENT_NOISE`.
+    pub fn set_measurement_noise(&mut self, r: Variance) {
+        self.measurement_noise = r.max(Variance::from_num(0));
+    }
+
+    /// Obtain the Kalman filter's estimate of the distance and its rate of
+    /// change (negative when approaching).
+    ///
+    /// The filter assumes a constant velocity between readings, and is
+    /// updated with every successfully measured distance. It restarts when
+    /// readings are more than a second apart.
+    ///
+    /// Returns `None` if there are no successful measurements since the
+    /// filter was last reset.
+    pub fn estimate(&self) -> Option<(Distance, Velocity)> {
+        self.kalman.as_ref().map(|k| {
+            (
+                Distance::saturating_from_num(k.distance),
+                Velocity::saturating_from_num(k.velocity),
+            )
+        })
+    }
+
+    /// Updates the Kalman filter with a distance measured at `at`.
+    fn update_estimate(&mut self, at: Instant<LRCLOCK>, z: Distance) {
+        let z = I32F32::from_num(z);
+        let (q, r) = (self.process_noise, self.measurement_noise);
+
+        let dt = self
+            .kalman
+            .as_ref()
+            .and_then(|k| at.checked_duration_since(&k.at))
+            .and_then(|dt| Microseconds::<u32>::try_from(dt).ok())
+            .filter(|dt| *dt <= KALMAN_MAX_GAP);
+        let (k, dt) = match (self.kalman.as_mut(), dt) {
+            (Some(k), Some(dt)) => (k, I32F32::from_num(dt.0) / 1_000_000),
+            _ => {
+                self.kalman = Some(Kalman {
+                    at,
+                    distance: z,
+                    velocity: I32F32::from_num(0),
+                    p: [r, I32F32::from_num(0), INITIAL_VELOCITY_VARIANCE],
+                });
+                return;
+            }
+        };
+
+        // Predict. All operations saturate, as large noise parameters can
+        // otherwise overflow the covariance.
+        let dt2 = dt.saturating_mul(dt);
+        let dt3 = dt2.saturating_mul(dt);
+        let [p00, p01, p11] = k.p;
+        let distance = k.distance.saturating_add(k.velocity.saturating_mul(dt));
+        let p00 = p00
+            .saturating_add(dt.saturating_mul(p01).saturating_mul_int(2))
+            .saturating_add(dt2.saturating_mul(p11))
+            .saturating_add(q.saturating_mul(dt3).saturating_mul(dt) / 4);
+        let p01 = p01
+            .saturating_add(dt.saturating_mul(p11))
+            .saturating_add(q.saturating_mul(dt3) / 2);
+        let p11 = p11.saturating_add(q.saturating_mul(dt2));
+
+        // Update.
+        let s = p00.saturating_add(r);
+        let (k0, k1) = match (p00.checked_div(s), p01.checked_div(s)) {
+            (Some(k0), Some(k1)) => (k0, k1),
+            _ => (I32F32::from_num(0), I32F32::from_num(0)),
+        };
+        let residual = z.saturating_sub(distance);
+        let one_minus_k0 = I32F32::from_num(1).saturating_sub(k0);
+        k.distance = distance.saturating_add(k0.saturating_mul(residual));
+        k.velocity = k.velocity.saturating_add(k1.saturating_mul(residual));
+        k.p = [
+            one_minus_k0.saturating_mul(p00),
+            one_minus_k0.saturating_mul(p01),
+            p11.saturating_sub(k1.saturating_mul(p01)),
+        ];
+        k.at = at;
+    }
+
+    /// Discards all distances used for filtering, and restarts the Kalman
+    /// filter.
+    pub fn reset_filter(&mut self) {
+        self.window.clear();
+        self.kalman = None;
+    }
+
+    /// Ends the measurement in progress with `measurement`, or restarts it at
+    /// `measurement.end` if `retry` is set, it failed transiently and retries
+    /// remain.
+    fn complete(&mut self, measurement: Measurement<LRCLOCK, HRCLOCK>, retry: bool) -> Progress {
+        if let Err(error) = measurement.result {
+            if retry && error.is_transient() && self.retries < self.max_retries {
+                self.retries += 1;
+                self.begin(measurement.end);
+                return Progress::Retrying;
+            }
+        }
+
+        self.retries = 0;
+        self.last_phase = self.phase();
+        self.en.set_low().ok();
+        self.state = State::Idle;
+        self.record(measurement);
+        Progress::Completed
+    }
+
+    /// Records a completed measurement.
+    fn record(&mut self, measurement: Measurement<LRCLOCK, HRCLOCK>) {
+        if let Ok(distance) = measurement.result {
+            if self.window.is_full() {
+                self.window.pop_front();
+            }
+            self.window.push_back(distance).ok();
+            self.update_estimate(measurement.start, distance);
+        }
+
+        self.consecutive_errors = match measurement.result {
+            Ok(_) => 0,
+            Err(_) => self.consecutive_errors.saturating_add(1),
+        };
+        if self.queue.is_full() {
+            self.queue.pop_front();
+        }
+        self.queue.push_back(measurement).ok();
+        if K > 0 {
+            if self.history.is_full() {
+                self.history.pop_front();
+            }
+            self.history.push_back(measurement).ok();
+        }
+        self.last = Some(measurement);
+        self.completions = self.completions.wrapping_add(1);
+    }
+
+    /// Obtain the latest time at which the measurement in progress completes.
+    ///
+    /// This is when the measurement would time out, although it may complete
+    /// earlier. If that time has already passed, the measurement completes
+    /// on the next call into the driver, so `now` is returned.
+    ///
+    /// Returns `None` if no measurement is in progress, or the sensor is still
+    /// powering up.
+    pub fn expected_completion(&self, now: Instant<LRCLOCK>) -> Option<Instant<LRCLOCK>>
+    where
+        <LRCLOCK as Clock>::T: TryFrom<u32>,
+    {
+        match self.state {
+            State::Measuring { start, .. } => start
+                .checked_add(self.timeout)
+                .map(|deadline| core::cmp::max(deadline, now)),
+            State::Idle | State::PoweringUp { .. } => None,
+        }
+    }
+
+    /// Determines if a measurement is in progress.
+    ///
+    /// Like `phase()`, timeouts are only detected on the next call that takes
+    /// the current time.
+    pub fn is_measuring(&self) -> bool {
+        !matches!(self.state, State::Idle)
+    }
+
+    /// Obtain the phase of the measurement in progress, if any.
+    pub fn phase(&self) -> MeasurementPhase {
+        match self.state {
+            State::Idle => MeasurementPhase::Idle,
+            State::PoweringUp { .. } => MeasurementPhase::PoweringUp,
+            State::Measuring { ref state, .. } => match state {
+                MeasurementState::AfterTriggerRising => MeasurementPhase::TriggerHigh,
+                MeasurementState::AfterTriggerFalling { .. } => MeasurementPhase::AwaitingEcho,
+                MeasurementState::AfterEchoRising { .. } => MeasurementPhase::EchoHigh,
+            },
+        }
+    }
+
+    /// Returns the currently set state of the trigger pin.
+    pub fn is_trig_high(&self) -> bool {
+        self.trig.is_set_high().unwrap_or(false)
+    }
+
+    /// Detects a timeout of the measurement in progress, if any, retrying it
+    /// if configured (see `set_max_retries()`).
+    ///
+    /// Timeouts are otherwise only detected on the next call that takes the
+    /// current time, so this should be called periodically (e.g. once
+    /// `expected_completion()` has passed) if no events are expected. If
+    /// `Progress::Retrying` is returned, the caller must pass
+    /// `Event::TriggerComplete` to `process()` after a duration of
+    /// `TRIGGER_WIDTH`.
+    pub fn tick(&mut self, at: Instant<LRCLOCK>) -> Progress {
+        self.poll(at, true)
+    }
+
+    /// Handles time-based driver state machine transitions, only retrying
+    /// failed measurements if `retry` is set.
+    ///
+    /// The trigger pin is driven low whenever a measurement is ended here, as
+    /// well as once `TRIGGER_TIMEOUT` has elapsed without
+    /// `Event::TriggerComplete`, so that a late event cannot stretch the
+    /// trigger pulse. The echo is still waited for in the latter case.
+    fn poll(&mut self, at: Instant<LRCLOCK>, retry: bool) -> Progress {
+        match self.state {
+            State::Measuring {
+                start,
+                ref mut state,
+            } => {
+                let elapsed = at
+                    .checked_duration_since(&start)
+                    .and_then(|elapsed| Microseconds::<u32>::try_from(elapsed).ok());
+                // `at` preceding `start`, or an elapsed time that cannot be
+                // represented, is treated as the timeout having elapsed.
+                let error = if elapsed.map_or(true, |elapsed| elapsed >= self.timeout) {
+                    Some(match state {
+                        MeasurementState::AfterTriggerRising => Error::Timeout,
+                        MeasurementState::AfterTriggerFalling { forced: true } => {
+                            Error::TriggerTimeout
+                        }
+                        MeasurementState::AfterTriggerFalling { forced: false } => Error::NoEcho,
+                        MeasurementState::AfterEchoRising { .. } => Error::EchoStuckHigh,
+                    })
+                } else {
+                    if elapsed.map_or(false, |elapsed| elapsed >= TRIGGER_TIMEOUT)
+                        && matches!(state, MeasurementState::AfterTriggerRising)
+                    {
+                        // `Event::TriggerComplete` may only be late: end the
+                        // trigger pulse, but keep waiting for the echo.
+                        self.trig.set_low().ok();
+                        *state = MeasurementState::AfterTriggerFalling { forced: true };
+                    }
+                    None
+                };
+
+                match error {
+                    Some(error) => {
+                        self.trig.set_low().ok();
+                        self.complete(
+                            Measurement {
+                                start,
+                                end: at,
+                                result: Err(error),
+                                echo_width: None,
+                                echo_rise: None,
+                                echo_fall: None,
+                            },
+                            retry,
+                        )
+                    }
+                    None => Progress::Pending,
+                }
+            }
+            State::PoweringUp { since, .. } => {
+                let timed_out = at
+                    .checked_duration_since(&since)
+                    .and_then(|elapsed| Microseconds::<u32>::try_from(elapsed).ok())
+                    .map_or(true, |elapsed| elapsed >= self.settle_timeout);
+                if timed_out {
+                    self.complete(
+                        Measurement {
+                            start: since,
+                            end: at,
+                            result: Err(Error::PowerTimeout),
+                            echo_width: None,
+                            echo_rise: None,
+                            echo_fall: None,
+                        },
+                        retry,
+                    )
+                } else {
+                    Progress::Pending
+                }
+            }
+            State::Idle => Progress::Pending,
+        }
+    }
+
+    /// Process an event.
+    ///
+    /// Returns `Ok(true)` if the event resulted in a measurement being
+    /// completed. Measurements that are retried (see `set_max_retries()`) are
+    /// not considered completed. If the measurement in progress had timed
+    /// out, the event is discarded, as it belongs to that measurement.
+    pub fn process(&mut self, event: Event<HRCLOCK>, at: Instant<LRCLOCK>) -> Result<bool, Error> {
+        match self.poll(at, true) {
+            Progress::Completed => return Ok(true),
+            Progress::Retrying => return Ok(false),
+            Progress::Pending => {}
+        }
+
+        match self.state {
+            State::Idle => Err(Error::Unexpected),
+            State::PoweringUp {
+                ref mut remaining, ..
+            } => {
+                if let Event::PowerTick = event {
+                    *remaining = remaining.saturating_sub(1);
+                    if *remaining == 0 {
+                        self.begin(at);
+                    }
+                    Ok(false)
+                } else {
+                    Err(Error::Unexpected)
+                }
+            }
+            State::Measuring {
+                start,
+                ref mut state,
+            } => {
+                match state {
+                    MeasurementState::AfterTriggerRising => {
+                        if let Event::TriggerComplete = event {
+                            self.trig.set_low().ok();
+                            *state = MeasurementState::AfterTriggerFalling { forced: false };
+                        } else {
+                            return Err(Error::Unexpected);
+                        }
+                    }
+                    MeasurementState::AfterTriggerFalling { forced } => match event {
+                        Event::EchoInterrupt(rise) => {
+                            *state = MeasurementState::AfterEchoRising { rise };
+                        }
+                        // Late `Event::TriggerComplete`, the trigger pin
+                        // having already been driven low by `poll()`.
+                        Event::TriggerComplete if *forced => *forced = false,
+                        _ => return Err(Error::Unexpected),
+                    },
+                    MeasurementState::AfterEchoRising { rise } => {
+                        if let Event::EchoInterrupt(fall) = event {
+                            // `None` if the falling edge precedes the rising edge, or the
+                            // clock cannot be converted.
+                            let width = fall
+                                .checked_duration_since(rise)
+                                .and_then(|width| Microseconds::<u32>::try_from(width).ok());
+
+                            // Ignore the rising edge being delivered more than once.
+                            if width.map_or(false, |width| width <= self.duplicate_echo_window) {
+                                return Ok(false);
+                            }
+
+                            let rise = *rise;
+                            let (result, echo_width) = match width {
+                                Some(width) => {
+                                    // Clamp width to timeout.
+                                    // Because it should be impossible for the width to exceed the
+                                    // timeout unless the two timers are derived from the same
+                                    // clock / have significantly different precision.
+                                    let echo_width =
+                                        EchoWidth::new(core::cmp::min(width, self.timeout));
+                                    let result = if echo_width.duration() < self.min_echo_width {
+                                        Err(Error::TooShort)
+                                    } else if echo_width.duration() > self.max_echo_width {
+                                        Err(Error::TooLong)
+                                    } else {
+                                        self.distance(echo_width).ok_or(Error::Unexpected)
+                                    };
+                                    (result, Some(echo_width.duration()))
+                                }
+                                None => (Err(Error::Unexpected), None),
+                            };
+                            let measurement = Measurement {
+                                start,
+                                end: at,
+                                result,
+                                echo_width,
+                                echo_rise: Some(rise),
+                                echo_fall: Some(fall),
+                            };
+                            return Ok(self.complete(measurement, true) == Progress::Completed);
+                        } else {
+                            return Err(Error::Unexpected);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Builder for `Sr04` instances with several options configured.
+///
+/// Every option defaults to the same value as for `Sr04::new()`. The sizes of
+/// the measurement queue, filter window and history are chosen through the
+/// const generic parameters of the built `Sr04`.
+#[derive(Debug, Copy, Clone)]
+pub struct Sr04Builder {
+    timeout: Microseconds,
+    settle_ticks: u8,
+    settle_timeout: Microseconds,
+    scaling_factor: ScalingFactor,
+    gain: Distance,
+    offset: DistanceOffset,
+    max_retries: u8,
+    min_echo_width: Microseconds,
+    max_echo_width: Microseconds,
+    duplicate_echo_window: Microseconds,
+}
+
+impl Default for Sr04Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sr04Builder {
+    /// Create a new builder with all options at their defaults.
+    pub fn new() -> Self {
+        Self {
+            timeout: TIMEOUT,
+            settle_ticks: 0,
+            settle_timeout: SETTLE_TIMEOUT,
+            scaling_factor: ScalingFactor::from_num(SCALING_FACTOR),
+            gain: distance!(1),
+            offset: DistanceOffset::from_num(0),
+            max_retries: 0,
+            min_echo_width: MINIMUM_ECHO_WIDTH,
+            max_echo_width: MAXIMUM_ECHO_WIDTH,
+            duplicate_echo_window: DUPLICATE_ECHO_WINDOW,
+        }
+    }
+
+    /// See `Sr04::with_timeout()`.
+    pub fn timeout(mut self, timeout: Microseconds) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Number of `Event::PowerTick`s to wait for after powering up the
+    /// sensor, for sensors built with `build_with_enable()`.
+    ///
+    /// See `Sr04::with_enable()`. Defaults to zero.
+    pub fn settle_ticks(mut self, ticks: u8) -> Self {
+        self.settle_ticks = ticks;
+        self
+    }
+
+    /// See `Sr04::set_settle_timeout()`.
+    pub fn settle_timeout(mut self, timeout: Microseconds) -> Self {
+        self.settle_timeout = timeout;
+        self
+    }
+
+    /// See `Sr04::set_scaling_factor()`.
+    ///
+    /// Overrides any earlier call to `temperature()`.
+    pub fn scaling_factor(mut self, factor: ScalingFactor) -> Self {
+        self.scaling_factor = factor;
+        self
+    }
+
+    /// See `Sr04::set_temperature()`.
+    ///
+    /// Overrides any earlier call to `scaling_factor()`.
+    pub fn temperature(mut self, celsius: Temperature) -> Self {
+        self.scaling_factor = scaling_factor_at(celsius);
+        self
+    }
+
+    /// See `Sr04::set_calibration()`.
+    pub fn calibration(mut self, gain: Distance, offset: DistanceOffset) -> Self {
+        self.gain = gain;
+        self.offset = offset;
+        self
+    }
+
+    /// See `Sr04::set_max_retries()`.
+    pub fn max_retries(mut self, n: u8) -> Self {
+        self.max_retries = n;
+        self
+    }
+
+    /// See `Sr04::set_min_echo_width()`.
+    pub fn min_echo_width(mut self, width: Microseconds) -> Self {
+        self.min_echo_width = width;
+        self
+    }
+
+    /// See `Sr04::set_max_echo_width()`.
+    pub fn max_echo_width(mut self, width: Microseconds) -> Self {
+        self.max_echo_width = width;
+        self
+    }
+
+    /// See `Sr04::set_duplicate_echo_window()`.
+    pub fn duplicate_echo_window(mut self, window: Microseconds) -> Self {
+        self.duplicate_echo_window = window;
+        self
+    }
+
+    /// Create a configured `Sr04` instance.
+    pub fn build<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        LRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+    >(
+        self,
+        trig: TRIG,
+    ) -> Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K>
+    where
+        Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+        Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+    {
+        self.configure(Sr04::new(trig))
+    }
+
+    /// Create a configured `Sr04` instance for a sensor powered through `en`.
+    ///
+    /// See `Sr04::with_enable()`.
+    pub fn build_with_enable<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        LRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+        EN: OutputPin,
+    >(
+        self,
+        trig: TRIG,
+        en: EN,
+    ) -> Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K, EN>
+    where
+        Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+        Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+    {
+        self.configure(Sr04::with_enable(trig, en, self.settle_ticks))
+    }
+
+    /// Applies the configured options to `sensor`.
+    fn configure<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        LRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+        EN: OutputPin,
+    >(
+        self,
+        sensor: Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K, EN>,
+    ) -> Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K, EN>
+    where
+        Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+        Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+    {
+        let mut sensor = sensor.with_timeout(self.timeout);
+        sensor.set_settle_timeout(self.settle_timeout);
+        sensor.set_scaling_factor(self.scaling_factor);
+        sensor.set_calibration(self.gain, self.offset);
+        sensor.set_max_retries(self.max_retries);
+        sensor.set_min_echo_width(self.min_echo_width);
+        sensor.set_max_echo_width(self.max_echo_width);
+        sensor.set_duplicate_echo_window(self.duplicate_echo_window);
+        sensor
+    }
+}
+
+/// Measures with several `Sr04`s in turn, so that no two sensors are ever
+/// triggered at once.
+///
+/// Sensors sharing a field of view pick up each other's pulses when fired
+/// together. Here, the next sensor is only triggered once the previous one
+/// has completed its measurement or timed out.
+///
+/// `poll()` drives the schedule and should be called periodically, as well as
+/// whenever `process()` completes a measurement.
+pub struct Sr04Array<TRIG, HRCLOCK: Clock, LRCLOCK: Clock, const N: usize> {
+    /// Sensors, in the order they are triggered.
+    sensors: [Sr04<TRIG, HRCLOCK, LRCLOCK>; N],
+    /// Index of the sensor last triggered.
+    current: usize,
+    /// Whether any sensor has been triggered yet.
+    started: bool,
+}
+
+impl<TRIG: StatefulOutputPin, HRCLOCK: Clock, LRCLOCK: Clock, const N: usize>
+    Sr04Array<TRIG, HRCLOCK, LRCLOCK, N>
+where
+    Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+    Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+{
+    /// Create a new `Sr04Array` triggering `sensors` in order.
+    pub fn new(sensors: [Sr04<TRIG, HRCLOCK, LRCLOCK>; N]) -> Self {
+        Self {
+            sensors,
+            current: 0,
+            started: false,
+        }
+    }
+
+    /// Releases the sensors.
+    pub fn into_inner(self) -> [Sr04<TRIG, HRCLOCK, LRCLOCK>; N] {
+        self.sensors
+    }
+
+    /// Obtain a sensor, e.g. to configure it.
+    ///
+    /// Triggering the sensor directly defeats the purpose of the scheduler.
+    pub fn sensor_mut(&mut self, index: usize) -> &mut Sr04<TRIG, HRCLOCK, LRCLOCK> {
+        &mut self.sensors[index]
+    }
+
+    /// Advances the schedule.
+    ///
+    /// Returns the index of the sensor triggered, if any, including a sensor
+    /// whose measurement timed out and was retried (see
+    /// `Sr04::set_max_retries()`). As with `Sr04::trigger()`, the caller must
+    /// then pass `Event::TriggerComplete` for that sensor to `process()` after
+    /// a duration of `TRIGGER_WIDTH`.
+    pub fn poll(&mut self, at: Instant<LRCLOCK>) -> Option<usize> {
+        if N == 0 {
+            return None;
+        }
+
+        let sensor = &mut self.sensors[self.current];
+        if sensor.tick(at) == Progress::Retrying {
+            return Some(self.current);
+        }
+        if sensor.is_measuring() {
+            return None;
+        }
+
+        if self.started {
+            self.current = (self.current + 1) % N;
+        }
+        self.started = true;
+        self.sensors[self.current].trigger(at).ok()?;
+        Some(self.current)
+    }
+
+    /// Process an event for the sensor at `index`.
+    ///
+    /// Returns `Ok(true)` if the event resulted in a measurement being
+    /// completed, in which case `poll()` should be called to trigger the next
+    /// sensor.
+    pub fn process(
+        &mut self,
+        index: usize,
+        event: Event<HRCLOCK>,
+        at: Instant<LRCLOCK>,
+    ) -> Result<bool, Error> {
+        self.sensors[index].process(event, at)
+    }
+
+    /// Obtain the last complete measurement of the sensor at `index`, if
+    /// any.
+    ///
+    /// This does not advance the schedule.
+    pub fn latest(&self, index: usize) -> Option<&Measurement<LRCLOCK, HRCLOCK>> {
+        self.sensors[index].last.as_ref()
+    }
+}
+
+/// Sequences "burst then sleep" ranging on an `Sr04` to save power.
+///
+/// Takes `burst` measurements back-to-back, fuses the successful ones into a
+/// single averaged reading, then leaves the sensor quiet for `sleep` before
+/// starting the next burst.
+///
+/// `step()` should be called whenever the caller wakes up (e.g. after
+/// `Sr04::process()` completes a measurement, or once
+/// `should_sleep_until()` has passed). Events still have to be passed to the
+/// sensor by the caller as usual.
+pub struct LowPowerRanger<LRCLOCK: Clock> {
+    /// Number of measurements per burst.
+    burst: u8,
+    /// Time to stay idle after a burst.
+    sleep: Microseconds<u32>,
+    /// Number of measurements the sensor had recorded when the ranger last
+    /// triggered it, while that measurement is pending.
+    pending: Option<u32>,
+    /// Number of measurements taken in the current burst.
+    taken: u8,
+    /// Number of successful measurements in the current burst.
+    successes: u8,
+    /// Sum of the successful measurements in the current burst.
+    sum: Distance,
+    /// Latest fused reading.
+    fused: Option<Distance>,
+    /// Time until which the sensor is left idle.
+    sleep_until: Option<Instant<LRCLOCK>>,
+}
+
+impl<LRCLOCK: Clock> LowPowerRanger<LRCLOCK>
+where
+    Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+    <LRCLOCK as Clock>::T: TryFrom<u32>,
+{
+    /// Creates a new ranger taking bursts of `burst` measurements separated by
+    /// `sleep`.
+    ///
+    /// A `burst` of zero is treated as one.
+    pub fn new(burst: u8, sleep: Microseconds<u32>) -> Self {
+        Self {
+            burst: burst.max(1),
+            sleep,
+            pending: None,
+            taken: 0,
+            successes: 0,
+            sum: Distance::from_num(0),
+            fused: None,
+            sleep_until: None,
+        }
+    }
+
+    /// Latest fused reading.
+    ///
+    /// `None` if no burst has completed yet, or if none of the measurements
+    /// in the last burst succeeded.
+    pub fn fused(&self) -> Option<Distance> {
+        self.fused
+    }
+
+    /// Time until which the sensor is left idle, if the ranger is sleeping.
+    ///
+    /// Calling `step()` before this instant does nothing.
+    pub fn should_sleep_until(&self) -> Option<Instant<LRCLOCK>> {
+        self.sleep_until
+    }
+
+    /// Advances the ranging sequence.
+    ///
+    /// Collects the result of the measurement triggered previously (if it has
+    /// completed), and triggers the next measurement of the burst unless the
+    /// ranger is sleeping.
+    ///
+    /// Returns `Ok(true)` if a burst was completed and a new fused reading is
+    /// available.
+    pub fn step<
+        TRIG: StatefulOutputPin,
+        HRCLOCK: Clock,
+        const N: usize,
+        const W: usize,
+        const K: usize,
+        EN: OutputPin,
+    >(
+        &mut self,
+        sensor: &mut Sr04<TRIG, HRCLOCK, LRCLOCK, N, W, K, EN>,
+        at: Instant<LRCLOCK>,
+    ) -> Result<bool, Error>
+    where
+        Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+    {
+        let mut completed = false;
+
+        if let Some(completions) = self.pending {
+            sensor.poll(at, false);
+            if sensor.completions == completions {
+                if sensor.is_measuring() {
+                    return Ok(false);
+                }
+                // Aborted without a measurement being recorded: trigger
+                // another one instead.
+                self.pending = None;
+            } else {
+                self.pending = None;
+                self.taken += 1;
+                if let Some(Ok(distance)) = sensor.last.map(|m| m.result) {
+                    self.successes += 1;
+                    self.sum = self.sum.saturating_add(distance);
+                }
+            }
+
+            if self.taken >= self.burst {
+                self.fused = if self.successes > 0 {
+                    Some(self.sum / self.successes as u32)
+                } else {
+                    None
+                };
+                self.taken = 0;
+                self.successes = 0;
+                self.sum = Distance::from_num(0);
+                self.sleep_until = Some(at.checked_add(self.sleep).unwrap_or(at));
+                completed = true;
+            }
+        }
+
+        if let Some(until) = self.sleep_until {
+            if at < until {
+                return Ok(completed);
+            }
+            self.sleep_until = None;
+        }
+
+        sensor.trigger(at)?;
+        self.pending = Some(sensor.completions);
+        Ok(completed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::mock::{Pin, UsClock};
+
+    type Sensor<const K: usize> = Sr04<Pin, UsClock, LrTimer, 8, 5, K>;
+
+    fn ms(ms: u32) -> Instant<LrTimer> {
+        Instant::new(ms)
+    }
+
+    fn us(us: u32) -> Instant<UsClock> {
+        Instant::new(us)
+    }
+
+    /// Completes the trigger pulse of the measurement started at `at`, and
+    /// passes an echo pulse `width` microseconds wide.
+    ///
+    /// Returns whether the falling edge completed the measurement.
+    fn echo<const N: usize, const W: usize, const K: usize>(
+        sensor: &mut Sr04<Pin, UsClock, LrTimer, N, W, K>,
+        at: u32,
+        width: u32,
+    ) -> bool {
+        sensor.process(Event::TriggerComplete, ms(at)).unwrap();
+        sensor
+            .process(Event::EchoInterrupt(us(1_000)), ms(at))
+            .unwrap();
+        sensor
+            .process(Event::EchoInterrupt(us(1_000 + width)), ms(at + 1))
+            .unwrap()
+    }
+
+    #[test]
+    fn ranger_bursts_then_sleeps() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        let mut ranger = LowPowerRanger::new(3, Microseconds(1_000_000));
+
+        assert!(!ranger.step(&mut sensor, ms(0)).unwrap());
+        assert!(sensor.is_measuring());
+        // Waking up early does not trigger the sensor again.
+        assert!(!ranger.step(&mut sensor, ms(0)).unwrap());
+
+        assert!(echo(&mut sensor, 0, 1_000));
+        let first = sensor.measurement(ms(1)).unwrap().result.unwrap();
+        assert!(!ranger.step(&mut sensor, ms(2)).unwrap());
+        assert!(sensor.is_measuring());
+
+        // Times out without an echo, which is left out of the fused reading.
+        sensor.process(Event::TriggerComplete, ms(2)).unwrap();
+        assert!(!ranger.step(&mut sensor, ms(62)).unwrap());
+        assert!(matches!(
+            sensor.measurement(ms(62)).unwrap().result,
+            Err(Error::NoEcho)
+        ));
+        assert!(sensor.is_measuring());
+
+        assert!(echo(&mut sensor, 62, 2_000));
+        let third = sensor.measurement(ms(63)).unwrap().result.unwrap();
+        assert!(ranger.step(&mut sensor, ms(64)).unwrap());
+        assert_eq!(ranger.fused(), Some((first + third) / 2));
+        assert!(ranger.should_sleep_until() == Some(ms(1_064)));
+        assert!(!sensor.is_measuring());
+
+        assert!(!ranger.step(&mut sensor, ms(500)).unwrap());
+        assert!(!sensor.is_measuring());
+
+        assert!(!ranger.step(&mut sensor, ms(1_064)).unwrap());
+        assert!(ranger.should_sleep_until().is_none());
+        assert!(sensor.is_measuring());
+        assert_eq!(ranger.fused(), Some((first + third) / 2));
+    }
+
+    #[test]
+    fn record_round_trips_distance() {
+        let mut m = Measurement::<LrTimer, UsClock>::new(
+            ms(1_000),
+            ms(1_012),
+            Ok(Distance::from_num(1.25)),
+        )
+        .unwrap();
+        m.echo_width = Some(Microseconds(7_225));
+        m.echo_rise = Some(us(5));
+        m.echo_fall = Some(us(7_230));
+
+        let record = m.to_record();
+        assert_eq!(record[0..4], 1_000_u32.to_le_bytes());
+        assert_eq!(record[4..8], 1_012_u32.to_le_bytes());
+        assert_eq!(record[8], 0);
+        assert_eq!(
+            record[9..13],
+            Distance::from_num(1.25).to_bits().to_le_bytes()
+        );
+        assert_eq!(record[13..15], 7_225_u16.to_le_bytes());
+
+        let decoded = Measurement::<LrTimer, UsClock>::from_record(&record).unwrap();
+        assert!(decoded.start == m.start);
+        assert!(decoded.end == m.end);
+        assert_eq!(decoded.result.unwrap(), Distance::from_num(1.25));
+        assert_eq!(decoded.echo_width, Some(Microseconds(7_225)));
+        // Edge times are not recorded.
+        assert!(decoded.echo_rise.is_none());
+        assert!(decoded.echo_fall.is_none());
+    }
+
+    #[test]
+    fn record_round_trips_errors() {
+        let errors = [
+            Error::InProgress,
+            Error::Timeout,
+            Error::NoEcho,
+            Error::EchoStuckHigh,
+            Error::TriggerTimeout,
+            Error::TooShort,
+            Error::TooLong,
+            Error::Unexpected,
+            Error::PowerTimeout,
+        ];
+        for error in errors.iter() {
+            let m = Measurement::<LrTimer, UsClock>::new(ms(5), ms(65), Err(*error)).unwrap();
+            let record = m.to_record();
+            assert_ne!(record[8], 0);
+            assert_eq!(record[9..15], [0, 0, 0, 0, 0xff, 0xff]);
+
+            let decoded = Measurement::<LrTimer, UsClock>::from_record(&record).unwrap();
+            assert!(decoded.start == m.start);
+            assert!(decoded.end == m.end);
+            assert_eq!(decoded.result.unwrap_err().code(), error.code());
+            assert_eq!(decoded.echo_width, None);
+        }
+
+        // Every error has its own code.
+        for (i, a) in errors.iter().enumerate() {
+            for b in &errors[i + 1..] {
+                assert_ne!(a.code(), b.code());
+            }
+        }
+    }
+
+    #[test]
+    fn record_rejects_unknown_code() {
+        let m = Measurement::<LrTimer, UsClock>::new(ms(0), ms(1), Err(Error::NoEcho)).unwrap();
+        let mut record = m.to_record();
+        record[8] = 0xaa;
+        assert!(Measurement::<LrTimer, UsClock>::from_record(&record).is_none());
+    }
+
+    #[test]
+    fn echo_width_clamps_to_timeout() {
+        let max = TIMEOUT.0 as u16;
+        assert_eq!(EchoWidth::new(Microseconds(1_234)).as_u16(), 1_234);
+        assert_eq!(
+            EchoWidth::new(Microseconds(TIMEOUT.0 - 1)).as_u16(),
+            max - 1
+        );
+        assert_eq!(EchoWidth::new(TIMEOUT).as_u16(), max);
+        assert_eq!(EchoWidth::new(Microseconds(TIMEOUT.0 + 1)).as_u16(), max);
+        assert_eq!(EchoWidth::new(Microseconds(u32::MAX)).as_u16(), max);
+        assert_eq!(EchoWidth::new(Microseconds(u32::MAX)).duration(), TIMEOUT);
+    }
+
+    #[test]
+    fn shorter_timeout_fires_earlier() {
+        let mut default: Sensor<0> = Sr04::new(Pin::default());
+        let mut short: Sensor<0> = Sr04::new(Pin::default()).with_timeout(Microseconds(10_000));
+        for sensor in [&mut default, &mut short] {
+            sensor.trigger(ms(0)).unwrap();
+            sensor.process(Event::TriggerComplete, ms(0)).unwrap();
+            assert_eq!(sensor.tick(ms(9)), Progress::Pending);
+        }
+
+        assert_eq!(short.tick(ms(10)), Progress::Completed);
+        let m = short.measurement(ms(10)).unwrap();
+        assert!(matches!(m.result, Err(Error::NoEcho)));
+        assert!(m.end == ms(10));
+
+        assert_eq!(default.tick(ms(10)), Progress::Pending);
+        assert_eq!(default.tick(ms(59)), Progress::Pending);
+        assert_eq!(default.tick(ms(60)), Progress::Completed);
+    }
+
+    #[test]
+    fn echo_clamped_to_configured_timeout() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default()).with_timeout(Microseconds(10_000));
+        sensor.trigger(ms(0)).unwrap();
+        assert!(echo(&mut sensor, 0, 15_000));
+
+        let m = *sensor.measurement(ms(1)).unwrap();
+        assert_eq!(m.echo_width, Some(Microseconds(10_000)));
+        let expected = sensor.distance(EchoWidth::new(Microseconds(10_000)));
+        assert_eq!(m.result.ok(), expected);
+    }
+
+    #[test]
+    fn timeout_capped_at_default() {
+        let sensor: Sensor<0> = Sr04::new(Pin::default()).with_timeout(Microseconds(100_000));
+        assert_eq!(sensor.timeout, TIMEOUT);
+    }
+
+    #[test]
+    fn median_ignores_spike() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        assert_eq!(sensor.median_distance(), None);
+
+        for (i, width) in [2_000, 2_010, 20_000, 1_990, 2_000].iter().enumerate() {
+            let at = 10 * i as u32;
+            sensor.trigger(ms(at)).unwrap();
+            assert!(echo(&mut sensor, at, *width));
+        }
+
+        let flat = sensor.distance(EchoWidth::new(Microseconds(2_000)));
+        assert_eq!(sensor.median_distance(), flat);
+        assert!(sensor.filtered_measurement() > flat);
+    }
+
+    #[test]
+    fn median_of_even_count_averages_middle() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        for (i, width) in [1_000, 3_000].iter().enumerate() {
+            let at = 10 * i as u32;
+            sensor.trigger(ms(at)).unwrap();
+            assert!(echo(&mut sensor, at, *width));
+        }
+
+        let low = sensor
+            .distance(EchoWidth::new(Microseconds(1_000)))
+            .unwrap();
+        let high = sensor
+            .distance(EchoWidth::new(Microseconds(3_000)))
+            .unwrap();
+        assert_eq!(sensor.median_distance(), Some(low + (high - low) / 2));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn measurement_serde_round_trip() {
+        let mut m =
+            Measurement::<LrTimer, UsClock>::new(ms(10), ms(22), Ok(Distance::from_num(1.5)))
+                .unwrap();
+        m.echo_width = Some(Microseconds(8_669));
+        m.echo_rise = Some(us(3));
+        m.echo_fall = Some(us(8_672));
+
+        let json = serde_json::to_string(&m).unwrap();
+        // Instants are represented by their raw tick counts.
+        assert!(json.contains("\"start\":10,"));
+        assert!(json.contains("\"echo_fall\":8672"));
+
+        let decoded: Measurement<LrTimer, UsClock> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.start == m.start);
+        assert!(decoded.end == m.end);
+        assert_eq!(decoded.result.unwrap(), Distance::from_num(1.5));
+        assert_eq!(decoded.echo_width, m.echo_width);
+        assert!(decoded.echo_rise == m.echo_rise);
+        assert!(decoded.echo_fall == m.echo_fall);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn failed_measurement_serde_round_trip() {
+        let m = Measurement::<LrTimer, UsClock>::new(ms(10), ms(70), Err(Error::NoEcho)).unwrap();
+
+        let json = serde_json::to_string(&m).unwrap();
+        let decoded: Measurement<LrTimer, UsClock> = serde_json::from_str(&json).unwrap();
+        assert!(matches!(decoded.result, Err(Error::NoEcho)));
+        assert_eq!(decoded.echo_width, None);
+        assert!(decoded.echo_rise.is_none());
+        assert!(decoded.echo_fall.is_none());
+    }
+
+    #[test]
+    fn short_echo_accepted_with_lower_floor() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.trigger(ms(0)).unwrap();
+        assert!(echo(&mut sensor, 0, 150));
+        let m = *sensor.measurement(ms(1)).unwrap();
+        assert!(matches!(m.result, Err(Error::TooShort)));
+
+        sensor.set_min_echo_width(Microseconds(100));
+        sensor.trigger(ms(10)).unwrap();
+        assert!(echo(&mut sensor, 10, 150));
+        let m = *sensor.measurement(ms(11)).unwrap();
+        assert_eq!(m.echo_width, Some(Microseconds(150)));
+        assert_eq!(
+            m.result.ok(),
+            sensor.distance(EchoWidth::new(Microseconds(150)))
+        );
+    }
+
+    #[test]
+    fn history_keeps_newest_first() {
+        let mut sensor: Sensor<3> = Sr04::new(Pin::default());
+        assert_eq!(sensor.history().count(), 0);
+
+        let widths = [1_000, 2_000, 3_000, 4_000];
+        for (i, width) in widths.iter().enumerate() {
+            let at = 10 * i as u32;
+            sensor.trigger(ms(at)).unwrap();
+            assert!(echo(&mut sensor, at, *width));
+        }
+
+        let history: Vec<_> = sensor.history().map(|m| m.echo_width.unwrap().0).collect();
+        assert_eq!(history, [4_000, 3_000, 2_000]);
+        let newest = *sensor.history().next().unwrap();
+        let last = sensor.measurement(ms(31)).unwrap();
+        assert!(last.start == newest.start);
+        assert_eq!(last.echo_width, Some(Microseconds(4_000)));
+    }
+
+    #[test]
+    fn history_enabled_by_default() {
+        let mut sensor: Sr04<Pin, UsClock, LrTimer> = Sr04::new(Pin::default());
+        for i in 0..10 {
+            sensor.trigger(ms(10 * i)).unwrap();
+            assert!(echo(&mut sensor, 10 * i, 1_000));
+        }
+        assert_eq!(sensor.history().count(), 8);
+    }
+
+    /// Measures each of `widths` in turn, 100 ms apart from `from`, a width of
+    /// zero giving no echo.
+    fn measure_widths<const K: usize>(sensor: &mut Sensor<K>, from: u32, widths: &[u32]) {
+        for (i, width) in widths.iter().enumerate() {
+            let at = from + 100 * i as u32;
+            sensor.trigger(ms(at)).unwrap();
+            if *width == 0 {
+                sensor.process(Event::TriggerComplete, ms(at)).unwrap();
+                assert_eq!(sensor.tick(ms(at + 60)), Progress::Completed);
+            } else {
+                assert!(echo(sensor, at, *width));
+            }
+        }
+    }
+
+    #[test]
+    fn confidence_high_for_consistent_readings() {
+        let mut sensor: Sensor<4> = Sr04::new(Pin::default());
+        assert_eq!(sensor.confidence(), 0);
+        measure_widths(&mut sensor, 0, &[2_000; 4]);
+        assert!(sensor.confidence() >= 250);
+        assert_eq!(
+            sensor.last_detailed().unwrap().confidence,
+            sensor.confidence()
+        );
+    }
+
+    #[test]
+    fn confidence_lower_for_scattered_readings() {
+        let mut consistent: Sensor<4> = Sr04::new(Pin::default());
+        measure_widths(&mut consistent, 0, &[2_000; 4]);
+        let mut scattered: Sensor<4> = Sr04::new(Pin::default());
+        measure_widths(&mut scattered, 0, &[1_000, 3_000, 1_000, 3_000]);
+
+        // A spread of ~0.17 m, well above `CONFIDENCE_SPREAD`.
+        assert!(scattered.confidence() < 128);
+        assert!(scattered.confidence() < consistent.confidence());
+    }
+
+    #[test]
+    fn confidence_penalizes_errors() {
+        let mut sensor: Sensor<4> = Sr04::new(Pin::default());
+        measure_widths(&mut sensor, 0, &[2_000, 0, 2_000, 0]);
+        assert_eq!(sensor.history().filter(|m| m.result.is_err()).count(), 2);
+
+        // Consistent successes, but only half of the measurements.
+        let confidence = sensor.confidence();
+        assert!((120..=128).contains(&confidence));
+
+        // Errors age out of the history.
+        measure_widths(&mut sensor, 400, &[2_000; 4]);
+        assert!(sensor.confidence() > confidence);
+    }
+
+    #[test]
+    fn times_out_without_echo() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.trigger(ms(0)).unwrap();
+        sensor.process(Event::TriggerComplete, ms(0)).unwrap();
+        assert_eq!(sensor.phase(), MeasurementPhase::AwaitingEcho);
+        assert_eq!(sensor.tick(ms(59)), Progress::Pending);
+
+        assert_eq!(sensor.tick(ms(61)), Progress::Completed);
+        let m = sensor.measurement(ms(61)).unwrap();
+        assert!(matches!(m.result, Err(Error::NoEcho)));
+        assert_eq!(m.echo_width, None);
+        assert!(!sensor.is_trig_high());
+    }
+
+    #[test]
+    fn times_out_with_echo_stuck_high() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.trigger(ms(0)).unwrap();
+        sensor.process(Event::TriggerComplete, ms(0)).unwrap();
+        sensor
+            .process(Event::EchoInterrupt(us(1_000)), ms(1))
+            .unwrap();
+        assert_eq!(sensor.phase(), MeasurementPhase::EchoHigh);
+        assert_eq!(sensor.tick(ms(59)), Progress::Pending);
+
+        assert_eq!(sensor.tick(ms(61)), Progress::Completed);
+        let m = sensor.measurement(ms(61)).unwrap();
+        assert!(matches!(m.result, Err(Error::EchoStuckHigh)));
+        assert_eq!(m.echo_width, None);
+        assert!(!sensor.is_measuring());
+    }
+
+    #[test]
+    fn falling_edge_before_rise_is_unexpected() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.trigger(ms(0)).unwrap();
+        sensor.process(Event::TriggerComplete, ms(0)).unwrap();
+        sensor
+            .process(Event::EchoInterrupt(us(5_000)), ms(1))
+            .unwrap();
+        assert!(sensor
+            .process(Event::EchoInterrupt(us(1_000)), ms(2))
+            .unwrap());
+
+        let m = sensor.measurement(ms(2)).unwrap();
+        assert!(matches!(m.result, Err(Error::Unexpected)));
+        assert_eq!(m.echo_width, None);
+        assert!(m.echo_rise == Some(us(5_000)));
+        assert!(m.echo_fall == Some(us(1_000)));
+    }
+
+    #[test]
+    fn time_before_start_is_timeout() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.trigger(ms(100)).unwrap();
+        assert_eq!(sensor.tick(ms(99)), Progress::Completed);
+
+        let m = sensor.measurement(ms(99)).unwrap();
+        assert!(matches!(m.result, Err(Error::Timeout)));
+        assert!(m.start == ms(100));
+        assert!(m.end == ms(99));
+        assert!(!sensor.is_trig_high());
+    }
+
+    #[test]
+    fn default_resolution_is_scaling_factor() {
+        assert_eq!(distance_resolution(), SCALING_FACTOR);
+        let sensor: Sensor<0> = Sr04::new(Pin::default());
+        assert_eq!(sensor.distance_resolution(), SCALING_FACTOR);
+    }
+
+    #[test]
+    fn resolution_follows_configuration() {
+        let mut sensor: Sensor<0> = Sr04::new(Pin::default());
+        sensor.set_calibration(distance!(2), DistanceOffset::from_num(-0.5));
+        assert_eq!(sensor.distance_resolution(), SCALING_FACTOR * 2);
+
+        // Sound is slower in the cold, so each microsecond covers less.
+        sensor.set_calibration(distance!(1), DistanceOffset::from_num(0));
+        sensor.set_temperature(temperature!(0));
+        assert!(sensor.distance_resolution() < SCALING_FACTOR);
     }
 }
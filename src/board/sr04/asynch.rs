@@ -0,0 +1,142 @@
+/// Async adapter for the HC-SR04 driver.
+///
+/// Drives the event-driven `Sr04` state machine from `embedded-hal-async`
+/// delays and echo pin edges, instead of timer and pin interrupts.
+use super::{Distance, Error, Event, MeasurementPhase, Progress, Sr04, TRIGGER_WIDTH};
+use core::convert::TryFrom;
+use embassy_futures::select::{select, Either};
+use embedded_hal::digital::v2::StatefulOutputPin;
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+use embedded_time::{
+    duration::{Generic, Microseconds},
+    Clock, Instant,
+};
+
+/// Async sensor.
+///
+/// `ECHO`: Echo pin.
+/// `DELAY`: Delay used to time the trigger pulse and measurement timeouts.
+///
+/// Edges on the echo pin are only waited for while the driver expects them,
+/// so the echo pin must latch edges (as `Wait` implementations built on
+/// EXTI typically do) for very short echo pulses to be caught.
+///
+/// Awaiting a single measurement:
+///
+/// ```ignore
+/// let mut sensor = AsyncSr04::new(Sr04::new(trig), echo, delay, &hrclock, &lrclock);
+/// let distance = sensor.measure().await?;
+/// ```
+pub struct AsyncSr04<'a, TRIG, ECHO, DELAY, HRCLOCK: Clock, LRCLOCK: Clock> {
+    /// Wrapped driver.
+    sensor: Sr04<TRIG, HRCLOCK, LRCLOCK>,
+    /// Echo pin.
+    echo: ECHO,
+    /// Delay.
+    delay: DELAY,
+    /// High-resolution clock, used to timestamp echo edges.
+    hrclock: &'a HRCLOCK,
+    /// Low-resolution clock, used to timestamp measurements.
+    lrclock: &'a LRCLOCK,
+}
+
+impl<'a, TRIG, ECHO, DELAY, HRCLOCK, LRCLOCK> AsyncSr04<'a, TRIG, ECHO, DELAY, HRCLOCK, LRCLOCK>
+where
+    TRIG: StatefulOutputPin,
+    ECHO: Wait,
+    DELAY: DelayNs,
+    HRCLOCK: Clock,
+    LRCLOCK: Clock,
+    Microseconds: TryFrom<Generic<<LRCLOCK as Clock>::T>>,
+    Microseconds: TryFrom<Generic<<HRCLOCK as Clock>::T>>,
+{
+    /// Create a new `AsyncSr04` instance.
+    pub fn new(
+        sensor: Sr04<TRIG, HRCLOCK, LRCLOCK>,
+        echo: ECHO,
+        delay: DELAY,
+        hrclock: &'a HRCLOCK,
+        lrclock: &'a LRCLOCK,
+    ) -> Self {
+        Self {
+            sensor,
+            echo,
+            delay,
+            hrclock,
+            lrclock,
+        }
+    }
+
+    /// Releases the wrapped driver, echo pin and delay.
+    pub fn into_parts(self) -> (Sr04<TRIG, HRCLOCK, LRCLOCK>, ECHO, DELAY) {
+        (self.sensor, self.echo, self.delay)
+    }
+
+    /// Obtain the wrapped driver, e.g. to configure it or inspect its
+    /// measurements.
+    pub fn sensor(&mut self) -> &mut Sr04<TRIG, HRCLOCK, LRCLOCK> {
+        &mut self.sensor
+    }
+
+    /// Takes a measurement, including any retries configured on the wrapped
+    /// driver.
+    pub async fn measure(&mut self) -> Result<Distance, Error> {
+        self.sensor.trigger(self.lr_now()?)?;
+
+        loop {
+            let completed = match self.sensor.phase() {
+                MeasurementPhase::Idle => true,
+                MeasurementPhase::TriggerHigh => {
+                    self.delay.delay_us(TRIGGER_WIDTH.0).await;
+                    self.sensor
+                        .process(Event::TriggerComplete, self.lr_now()?)?
+                }
+                MeasurementPhase::AwaitingEcho => self.edge(true).await?,
+                MeasurementPhase::EchoHigh => self.edge(false).await?,
+                // Only `NoEnable` sensors are wrapped, which never power up.
+                MeasurementPhase::PoweringUp => return Err(Error::Unexpected),
+            };
+
+            if completed {
+                return self
+                    .sensor
+                    .last
+                    .as_ref()
+                    .map_or(Err(Error::Unexpected), |m| m.result);
+            }
+        }
+    }
+
+    /// Waits for a rising (or falling) edge on the echo pin, or for the
+    /// measurement to time out.
+    ///
+    /// Returns `Ok(true)` if the measurement completed.
+    async fn edge(&mut self, rising: bool) -> Result<bool, Error> {
+        let echo = &mut self.echo;
+        let edge = async move {
+            if rising {
+                echo.wait_for_rising_edge().await
+            } else {
+                echo.wait_for_falling_edge().await
+            }
+        };
+
+        let timeout = self.delay.delay_us(self.sensor.timeout.0);
+        let woken = select(edge, timeout).await;
+
+        match woken {
+            Either::First(result) => {
+                result.map_err(|_| Error::Unexpected)?;
+                let at = self.hrclock.try_now().map_err(|_| Error::Unexpected)?;
+                self.sensor
+                    .process(Event::EchoInterrupt(at), self.lr_now()?)
+            }
+            Either::Second(()) => Ok(self.sensor.tick(self.lr_now()?) == Progress::Completed),
+        }
+    }
+
+    /// Obtain the current time on the low-resolution clock.
+    fn lr_now(&self) -> Result<Instant<LRCLOCK>, Error> {
+        self.lrclock.try_now().map_err(|_| Error::Unexpected)
+    }
+}
@@ -0,0 +1,108 @@
+/// Simulated HC-SR04 sensor for host-side development.
+///
+/// Exposes the same measurement-query surface as `Sr04`, but obtains
+/// distances from a user-supplied scene function instead of hardware events.
+use super::{Distance, Error, Measurement};
+use embedded_time::{Clock, Instant};
+
+/// Simulated sensor.
+///
+/// `LRCLOCK`: Clock used to record the start and end timestamps.
+/// `SCENE`: Function mapping the (simulated) time at which a measurement
+///          completes to the result of that measurement.
+pub struct SimSr04<LRCLOCK: Clock, SCENE> {
+    /// Scene function.
+    scene: SCENE,
+    /// Start time of the measurement in progress, if any.
+    start: Option<Instant<LRCLOCK>>,
+    /// Last measurement recorded.
+    last: Option<Measurement<LRCLOCK>>,
+}
+
+impl<LRCLOCK: Clock, SCENE> SimSr04<LRCLOCK, SCENE>
+where
+    SCENE: FnMut(Instant<LRCLOCK>) -> Result<Distance, Error>,
+{
+    /// Create a new `SimSr04` instance driven by `scene`.
+    pub fn new(scene: SCENE) -> Self {
+        Self {
+            scene,
+            start: None,
+            last: None,
+        }
+    }
+
+    /// Trigger the simulated sensor.
+    ///
+    /// Unlike `Sr04::trigger()`, no further events need to be supplied: the
+    /// measurement completes on the next call to `measurement()`.
+    pub fn trigger(&mut self, at: Instant<LRCLOCK>) -> Result<(), Error> {
+        match self.start {
+            None => {
+                self.start = Some(at);
+                Ok(())
+            }
+            Some(_) => Err(Error::InProgress),
+        }
+    }
+
+    /// Obtain the last complete measurement, if any.
+    ///
+    /// Completes any measurement in progress by sampling the scene at `at`.
+    /// Simulated measurements carry no echo pulse width or edge times.
+    pub fn measurement(&mut self, at: Instant<LRCLOCK>) -> Option<&Measurement<LRCLOCK>> {
+        if let Some(start) = self.start.take() {
+            self.last = Some(Measurement {
+                start,
+                end: at,
+                result: (self.scene)(at),
+                echo_width: None,
+                echo_rise: None,
+                echo_fall: None,
+            });
+        }
+
+        self.last.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::lrtimer::LrTimer;
+
+    #[test]
+    fn measures_scene() {
+        // Object approaching at 1 m/s from 2 m away.
+        let mut sensor = SimSr04::<LrTimer, _>::new(|at: Instant<LrTimer>| {
+            let ms = at.duration_since_epoch().integer();
+            Ok(Distance::from_num(2) - Distance::from_num(ms) / 1000)
+        });
+        assert!(sensor.measurement(Instant::new(0)).is_none());
+
+        sensor.trigger(Instant::new(100)).unwrap();
+        assert!(matches!(
+            sensor.trigger(Instant::new(110)),
+            Err(Error::InProgress)
+        ));
+
+        let m = *sensor.measurement(Instant::new(500)).unwrap();
+        assert!(m.start == Instant::new(100));
+        assert!(m.end == Instant::new(500));
+        assert_eq!(m.result.unwrap(), Distance::from_num(1.5));
+        assert!(m.echo_width.is_none());
+
+        // Nothing in progress: the last measurement is returned again.
+        let m = sensor.measurement(Instant::new(900)).unwrap();
+        assert!(m.end == Instant::new(500));
+        sensor.trigger(Instant::new(900)).unwrap();
+    }
+
+    #[test]
+    fn reports_scene_errors() {
+        let mut sensor = SimSr04::<LrTimer, _>::new(|_| Err(Error::NoEcho));
+        sensor.trigger(Instant::new(0)).unwrap();
+        let m = sensor.measurement(Instant::new(60)).unwrap();
+        assert!(matches!(m.result, Err(Error::NoEcho)));
+    }
+}
@@ -2,5 +2,11 @@
 use hdcomm_core::message::Message;
 use heapless::Deque;
 
+/// A `Queue` buffers items of type `T` in FIFO order.
+///
+/// Shared by message queues and any other payloads that need the same
+/// machinery.
+pub type Queue<T, const N: usize> = Deque<T, N>;
+
 /// A `MessageQueue` helps to queue messages for transmission.
-pub type MessageQueue<const N: usize> = Deque<Message, N>;
+pub type MessageQueue<const N: usize> = Queue<Message, N>;